@@ -1,158 +1,1077 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
 use tokio::process::Child;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 use uuid::Uuid;
 
+use crate::audit::{AuditEvent, AuditLog};
+use crate::capture::CaptureStore;
+use crate::clipboard::{self, ClipboardBackend};
 use crate::config::Config;
-use crate::tunnel;
+use crate::subdomain;
+use crate::tunnel::proxy::Proxy;
+use crate::tunnel::{self, Provider, TunnelError, TunnelEvent};
+use crate::update;
+
+/// Ports popular dev servers listen on, tried in order by `auto_port`
+const AUTO_PORT_CANDIDATES: [u16; 5] = [3000, 5173, 8000, 8080, 4200];
+
+/// How long to wait for each candidate port to accept a connection
+const AUTO_PORT_PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Number of recent requests the average payload sizes are taken over
+const RECENT_SIZES_WINDOW: usize = 20;
+
+/// Number of recent request durations kept for the latency graph
+const RECENT_DURATIONS_WINDOW: usize = 100;
+
+/// How serious a log line is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    Info,
+    Error,
+}
+
+/// How each line in the log pane is stamped
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTimestamps {
+    /// Wall clock time, e.g. `[14:02:31]`
+    #[default]
+    Clock,
+    /// Time since the line before, e.g. `[+2.1s]`
+    Relative,
+    /// No timestamp, leaving the whole width to the message
+    None,
+}
+
+impl LogTimestamps {
+    fn next(self) -> Self {
+        match self {
+            LogTimestamps::Clock => LogTimestamps::Relative,
+            LogTimestamps::Relative => LogTimestamps::None,
+            LogTimestamps::None => LogTimestamps::Clock,
+        }
+    }
+}
+
+/// A line in the log pane and when it was added
+pub struct LogEntry {
+    pub at: chrono::DateTime<chrono::Local>,
+    pub message: String,
+}
 
 #[derive(PartialEq)]
 pub enum AppMode {
     Normal,
     ConfigPort,
     ConfigServerPort,
+    PickProvider,
+    ShowUrl,
+    ShowCapture,
 }
 
 pub struct App {
     pub port: u16,
+    /// Pick the port by probing common dev server ports when the tunnel starts
+    pub auto_port: bool,
+    /// Host the local service listens on, without IPv6 brackets
+    pub local_host: String,
+    pub local_socket: Option<PathBuf>,
+    pub forward_host_header: Option<String>,
+    pub base_path: Option<String>,
+    /// Headers the server adds to every response
+    pub response_headers: HashMap<String, String>,
+    /// Whether the local service speaks HTTPS, detected when the tunnel starts
+    pub local_https: bool,
     pub domain: Option<String>,
+    pub server_host: String,
     pub server_port: u16,
+    pub provider: Provider,
+    pub provider_args: Vec<String>,
+    pub servers: Vec<String>,
+    pub token: Option<String>,
+    pub check_dns: bool,
+    /// Re-register automatically when the connection to the server drops
+    pub reconnect: bool,
+    /// Reconnect attempts before the tunnel is marked failed, unlimited if `None`
+    pub max_reconnect_attempts: Option<u32>,
+    /// Address sent a copy of the tunnel's traffic
+    pub tee: Option<String>,
+    /// Most requests forwarded to the local service at once
+    pub max_concurrent: Option<usize>,
+    /// Requests being forwarded right now, and those queued behind the cap
+    pub in_flight: (usize, usize),
+    /// Structured record of lifecycle events, if one was configured
+    audit_log: Option<AuditLog>,
+    /// Recent request and response bodies, when capturing is enabled
+    pub captures: Option<CaptureStore>,
+    /// Capture shown in the popup, counted back from the newest
+    pub capture_selection: usize,
+    /// Lines scrolled down in the capture popup
+    pub capture_scroll: u16,
     pub tunnel_active: bool,
     pub tunnel_url: Option<String>,
     pub tunnel_process: Option<Child>,
-    pub logs: Vec<String>,
+    pub tunnel_task: Option<JoinHandle<()>>,
+    pub tunnel_drain: Option<watch::Sender<bool>>,
+    /// How long in-flight requests get to finish when the tunnel is stopped
+    pub drain_period: Duration,
+    pub logs: Vec<LogEntry>,
     pub log_offset: usize,
+    pub follow_logs: bool,
+    /// Maximum number of log lines kept, older ones are dropped
+    pub log_retention: usize,
+    /// Drop everything but errors from the log pane
+    pub silent_logs: bool,
+    /// How log lines are stamped in the log pane
+    pub log_timestamps: LogTimestamps,
+    /// Whether the TUI captures the mouse rather than leaving it to the terminal
+    pub mouse_capture: bool,
+    /// Where `c` copies the URL to, resolved from the config at startup
+    pub clipboard: ClipboardBackend,
+    /// Names the subdomain when no domain is set
     pub client_id: String,
+    /// Full UUID identifying this run, whatever style `client_id` uses
+    pub instance_id: Uuid,
     pub connection_error: Option<String>,
     pub mode: AppMode,
     pub config: Config,
     pub input_buffer: String,
+    pub tunnel_events: Option<mpsc::Receiver<TunnelEvent>>,
+    pub reconnect_count: u32,
+    pub last_disconnect_reason: Option<String>,
+    /// Bytes the server will still forward, if it enforces a traffic cap
+    pub quota_remaining: Option<u64>,
+    pub request_count: u64,
+    /// Request and response sizes of the most recent requests, newest last
+    pub recent_sizes: VecDeque<(usize, usize)>,
+    /// How long the most recent requests took in milliseconds, newest last
+    pub recent_durations: VecDeque<u64>,
+    pub provider_warning: Option<String>,
+    /// A newer release than the one running, if the startup check found one
+    pub update_available: Option<String>,
+    update_check: Option<oneshot::Receiver<Option<String>>>,
+    /// Providers offered by the picker along with whether each is installed
+    pub provider_choices: Vec<(Provider, bool)>,
+    pub provider_selection: usize,
 }
 
 impl App {
-    pub fn new(port: u16, domain: Option<String>, server_port: u16) -> Self {
-        // Load config
-        let config = Config::load().unwrap_or_else(|e| {
-            eprintln!("Error loading config: {}", e);
-            Config::default()
-        });
-        
-        // Command line arguments override config values
-        let port = if port != 8080 { port } else { config.port };
-        let server_port = if server_port != 8000 { server_port } else { config.server_port };
-        let domain = domain.or_else(|| config.domain.clone());
-        
-        // Update config with any command line overrides
-        let config = Config {
-            port,
-            server_port,
-            domain: domain.clone(),
-        };
-        
-        Self {
-            port,
-            domain,
-            server_port,
+    pub fn new(config: Config) -> Self {
+        let mut app = Self {
+            port: config.port,
+            auto_port: config.auto_port,
+            local_host: tunnel::client::strip_brackets(&config.local_host).to_string(),
+            local_socket: config.local_socket.clone(),
+            forward_host_header: config.forward_host_header.clone(),
+            base_path: config.base_path.clone(),
+            response_headers: config.response_headers.clone(),
+            local_https: false,
+            domain: config
+                .domain
+                .as_deref()
+                .map(|domain| tunnel::client::strip_scheme(domain).to_string()),
+            server_host: config.server_host.clone(),
+            server_port: config.server_port,
+            provider: config.provider,
+            provider_args: config.provider_args.clone(),
+            servers: config.servers.clone(),
+            token: config.token.clone(),
+            check_dns: config.check_dns,
+            reconnect: !config.no_reconnect,
+            max_reconnect_attempts: Some(config.max_reconnect_attempts).filter(|max| *max > 0),
+            tee: config.tee.clone(),
+            max_concurrent: config.max_concurrent.filter(|max| *max > 0),
+            in_flight: (0, 0),
+            audit_log: None,
+            captures: config.capture.as_ref().map(CaptureStore::new),
+            capture_selection: 0,
+            capture_scroll: 0,
             tunnel_active: false,
             tunnel_url: None,
             tunnel_process: None,
+            tunnel_task: None,
+            tunnel_drain: None,
+            drain_period: Duration::from_secs(config.drain_secs),
             logs: Vec::new(),
             log_offset: 0,
-            client_id: Uuid::new_v4().to_string(),
+            follow_logs: true,
+            log_retention: config.log_retention.max(1),
+            silent_logs: config.silent_logs,
+            log_timestamps: config.log_timestamps,
+            mouse_capture: !config.no_mouse,
+            clipboard: config.clipboard.resolve(),
+            client_id: subdomain::generate(config.subdomain_style),
+            instance_id: Uuid::new_v4(),
             connection_error: None,
             mode: AppMode::Normal,
             config,
             input_buffer: String::new(),
+            tunnel_events: None,
+            reconnect_count: 0,
+            last_disconnect_reason: None,
+            quota_remaining: None,
+            request_count: 0,
+            recent_sizes: VecDeque::new(),
+            recent_durations: VecDeque::new(),
+            provider_warning: None,
+            update_available: None,
+            update_check: None,
+            provider_choices: Vec::new(),
+            provider_selection: 0,
+        };
+
+        if let (Some(configured), Some(domain)) = (&app.config.domain, &app.domain) {
+            if configured != domain {
+                app.add_log(&format!(
+                    "Using domain {} (a domain is given without http:// or https://)",
+                    domain
+                ));
+            }
+        }
+        app.check_provider_installed();
+        app.check_port_conflict();
+        if let Some(path) = app.config.audit_log.clone() {
+            match AuditLog::open(&path) {
+                Ok(audit_log) => app.audit_log = Some(audit_log),
+                Err(e) => app.add_error(&format!("{:#}", e)),
+            }
+        }
+        if app.config.update_check {
+            app.start_update_check();
+        }
+        app
+    }
+
+    /// Looks for a newer release in the background, failing silently when offline
+    fn start_update_check(&mut self) {
+        let (sender, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = sender.send(update::newer_release().await.unwrap_or(None));
+        });
+        self.update_check = Some(receiver);
+    }
+
+    /// Picks up the result of the startup update check once it is ready
+    pub fn poll_update_check(&mut self) {
+        let Some(receiver) = &mut self.update_check else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(latest) => {
+                self.update_available = latest;
+                self.update_check = None;
+            }
+            Err(oneshot::error::TryRecvError::Empty) => {}
+            Err(oneshot::error::TryRecvError::Closed) => self.update_check = None,
+        }
+    }
+
+    /// A local port that doesn't collide with the tunnel server's listeners,
+    /// if the configured one does. Only matters when the server runs on
+    /// this machine, where it binds `server_port` and `server_port + 1`.
+    fn port_conflict_fix(&self) -> Option<u16> {
+        let local_server = self.provider == Provider::Builtin
+            && self.servers.is_empty()
+            && (self.server_host == "localhost"
+                || self
+                    .server_host
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback()));
+        let server_ports = [self.server_port, self.server_port.wrapping_add(1)];
+        if !local_server || !server_ports.contains(&self.port) {
+            return None;
         }
+
+        self.server_port.checked_add(2).or(self.server_port.checked_sub(1))
+    }
+
+    /// Warns when the local port collides with the tunnel server's, which
+    /// otherwise shows up as confusing bind or connect errors
+    pub fn check_port_conflict(&mut self) {
+        if let Some(suggested) = self.port_conflict_fix() {
+            self.add_log(&format!(
+                "Warning: port {} clashes with the tunnel server on {} (it listens on {} and {}). \
+                 Press 'p' to move your service, e.g. to {}.",
+                self.port,
+                self.server_host,
+                self.server_port,
+                self.server_port.wrapping_add(1),
+                suggested
+            ));
+        }
+    }
+
+    /// Warns up front if the selected provider's binary is missing, rather
+    /// than failing once the user tries to start the tunnel
+    pub fn check_provider_installed(&mut self) {
+        self.provider_warning = if self.provider.is_installed() {
+            None
+        } else {
+            Some(format!(
+                "{} not found. Install it first: {}",
+                self.provider.binary().unwrap_or_default(),
+                self.provider.install_hint()
+            ))
+        };
     }
 
     pub async fn start_tunnel(&mut self) -> Result<()> {
         self.connection_error = None;
-        self.add_log("Starting tunnel...");
+        self.add_log(&format!("Starting tunnel (instance {})...", self.instance_id));
         
-        let (sender, _) = mpsc::channel::<String>(100);
+        let (sender, receiver) = mpsc::channel::<TunnelEvent>(100);
+        self.tunnel_events = Some(receiver);
+        self.reconnect_count = 0;
+        self.last_disconnect_reason = None;
+        self.quota_remaining = None;
+
+        if self.max_concurrent.is_some() && self.provider != Provider::Builtin {
+            self.add_log(&format!(
+                "Warning: {} doesn't queue requests, only the builtin provider supports --max-concurrent",
+                self.provider.name()
+            ));
+        }
+
+        if self.captures.is_some() && self.provider != Provider::Builtin {
+            self.add_log(&format!(
+                "Warning: {} requests can't be captured, only the builtin provider supports --capture-bodies",
+                self.provider.name()
+            ));
+        }
+
+        if self.tee.is_some() && self.provider != Provider::Builtin {
+            self.add_log(&format!(
+                "Warning: {} traffic can't be teed, only the builtin provider supports --tee",
+                self.provider.name()
+            ));
+        }
+
+        if self.config.lazy_connect && self.provider != Provider::Builtin {
+            self.add_log(&format!(
+                "Warning: {} keeps its own connection open, only the builtin provider supports lazy_connect",
+                self.provider.name()
+            ));
+        }
+
+        if self.local_host != "localhost" && self.provider != Provider::Builtin {
+            self.add_log(&format!(
+                "Warning: {} always forwards to localhost, ignoring --local-host {}",
+                self.provider.name(),
+                self.local_host
+            ));
+        }
+
+        if self.local_socket.is_some() && self.provider != Provider::Builtin {
+            self.add_log(&format!(
+                "Warning: {} can't forward to a Unix socket, using port {} instead",
+                self.provider.name(),
+                self.port
+            ));
+        }
         
-        // Try to start the tunnel
-        match tunnel::client::start_tunnel(
-            self.port,
-            self.domain.clone(),
-            self.server_port,
-            self.client_id.clone(),
-            sender,
-        )
-        .await {
+        if let tunnel::client::LocalTarget::Tcp(..) | tunnel::client::LocalTarget::Tls(..) =
+            self.local_target()
+        {
+            if self.auto_port {
+                self.discover_port().await;
+            }
+            self.detect_local_scheme(self.port).await;
+        }
+
+        // Try to start the tunnel with the selected provider
+        let result = match self.provider {
+            Provider::Builtin => match self.resolve_server_addr().await {
+                Ok(server_addr) => {
+                    if self.check_dns {
+                        self.check_domain_dns(&server_addr).await;
+                    }
+                    let registration = tunnel::client::Registration {
+                        client_id: self.client_id.clone(),
+                        instance_id: self.instance_id.to_string(),
+                        domain: self.domain.clone(),
+                        token: self.token.clone(),
+                        host_header: self.forward_host_header.clone(),
+                        base_path: self.base_path.clone(),
+                        response_headers: self.response_headers.clone(),
+                        tls: self.config.tls.clone(),
+                        proxy: self.config.proxy.clone(),
+                        lazy_connect: self.config.lazy_connect,
+                    };
+                    let forwarding = tunnel::client::Forwarding {
+                        local_target: self.local_target(),
+                        reconnect: self.reconnect,
+                        max_reconnect_attempts: self.max_reconnect_attempts,
+                        tee: self.tee.clone(),
+                        max_concurrent: self.max_concurrent,
+                        capture_limit: self.captures.as_ref().map(CaptureStore::byte_limit),
+                    };
+                    tunnel::client::start_tunnel(forwarding, server_addr, registration, sender)
+                        .await
+                }
+                Err(err) => Err(err),
+            },
+            Provider::Ngrok => {
+                let (port, domain, args) = (self.port, self.domain.clone(), self.provider_args.clone());
+                tunnel::ngrok::start_tunnel(port, domain, args, self).await
+            }
+            Provider::Cloudflared => {
+                let (port, domain, args) = (self.port, self.domain.clone(), self.provider_args.clone());
+                tunnel::cloudflared::start_tunnel(port, domain, args, self).await
+            }
+            Provider::Localtunnel => {
+                let (port, domain, args) = (self.port, self.domain.clone(), self.provider_args.clone());
+                tunnel::localtunnel::start_tunnel(port, domain, args, self).await
+            }
+        };
+
+        match result {
             Ok(result) => {
                 let url = result.url.clone();  // Clone the URL before moving it
-                self.tunnel_process = Some(result.process);
+                self.tunnel_process = result.process;
+                self.tunnel_task = result.task;
+                self.tunnel_drain = result.drain;
                 self.tunnel_url = Some(result.url);
                 self.tunnel_active = true;
                 self.add_log(&format!("Tunnel established at: {}", url));
+                self.audit(AuditEvent::TunnelUp {
+                    url,
+                    provider: self.provider.name().to_string(),
+                    local: self.local_target().to_string(),
+                });
                 Ok(())
             }
             Err(err) => {
-                let error_msg = format!("Failed to start tunnel: {}", err);
-                self.add_log(&error_msg);
+                let error_msg = match err.downcast_ref::<TunnelError>() {
+                    Some(tunnel_err) => self.describe_tunnel_error(tunnel_err),
+                    None => format!("Failed to start tunnel: {:#}", err),
+                };
+                self.add_error(&error_msg);
+                self.audit(AuditEvent::TunnelError {
+                    error: error_msg.clone(),
+                });
                 self.connection_error = Some(error_msg);
                 Ok(()) // Return Ok to prevent app crash
             }
         }
     }
 
+    /// Asks a provider process to shut down gracefully and waits up to the
+    /// drain period for it, returning whether it exited
+    #[cfg(unix)]
+    async fn drain_process(&mut self, process: &mut Child) -> bool {
+        let Some(pid) = process.id() else {
+            return true;
+        };
+        if self.drain_period.is_zero() {
+            return false;
+        }
+        // 0 and out of range ids would signal a whole process group
+        let Some(pid) = libc::pid_t::try_from(pid).ok().filter(|pid| *pid > 0) else {
+            return false;
+        };
+
+        // Providers finish their open connections on SIGTERM
+        // SAFETY: `pid` is our own child, which hasn't been reaped yet
+        if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+            let e = std::io::Error::last_os_error();
+            self.add_error(&format!("Couldn't ask the provider to stop gracefully: {}", e));
+            return false;
+        }
+
+        matches!(timeout(self.drain_period, process.wait()).await, Ok(Ok(_)))
+    }
+
+    #[cfg(not(unix))]
+    async fn drain_process(&mut self, _process: &mut Child) -> bool {
+        false
+    }
+
+    /// The local service forwarded requests are delivered to. Only the
+    /// builtin provider can forward to a Unix socket.
+    pub fn local_target(&self) -> tunnel::client::LocalTarget {
+        match &self.local_socket {
+            Some(path) if self.provider == Provider::Builtin => {
+                tunnel::client::LocalTarget::Unix(path.clone())
+            }
+            _ if self.local_https => {
+                tunnel::client::LocalTarget::Tls(self.local_host.clone(), self.port)
+            }
+            _ => tunnel::client::LocalTarget::Tcp(self.local_host.clone(), self.port),
+        }
+    }
+
+    /// Switches to the first common dev server port with something listening,
+    /// keeping the configured port if none has
+    async fn discover_port(&mut self) {
+        for port in AUTO_PORT_CANDIDATES {
+            let probe = TcpStream::connect((self.local_host.as_str(), port));
+            if matches!(timeout(AUTO_PORT_PROBE_TIMEOUT, probe).await, Ok(Ok(_))) {
+                self.port = port;
+                self.add_log(&format!("Found a service on port {}, tunneling it", port));
+                return;
+            }
+        }
+
+        self.add_log(&format!(
+            "No service found on ports {:?}, using port {}",
+            AUTO_PORT_CANDIDATES, self.port
+        ));
+    }
+
+    /// Probes the local port so HTTPS dev servers are forwarded to over TLS
+    /// without any configuration
+    async fn detect_local_scheme(&mut self, port: u16) {
+        match tunnel::client::detect_https(&self.local_host, port).await {
+            Some(https) => {
+                self.local_https = https;
+                let scheme = if https { "HTTPS" } else { "HTTP" };
+                self.add_log(&format!("Local service on port {} speaks {}", port, scheme));
+            }
+            None => {
+                self.local_https = false;
+                self.add_log(&format!(
+                    "Nothing is listening on port {} yet, assuming HTTP",
+                    port
+                ));
+            }
+        }
+    }
+
+    /// Turns a tunnel error into a message saying what to do about it
+    fn describe_tunnel_error(&mut self, err: &TunnelError) -> String {
+        // The binary may have been removed since startup
+        if let TunnelError::ProviderNotInstalled { .. } = err {
+            self.check_provider_installed();
+        }
+
+        let mut message = format!("Failed to start tunnel: {}", err);
+        if let Some(hint) = err.hint() {
+            message.push_str(&format!(" - {}", hint));
+        }
+        if err.is_retryable() {
+            message.push_str(" (press 's' to retry)");
+        }
+        message
+    }
+
+    /// Picks the tunnel server to connect to, preferring the lowest latency
+    /// of the configured candidates
+    async fn resolve_server_addr(&mut self) -> Result<String> {
+        let first = self
+            .servers
+            .first()
+            .cloned()
+            .unwrap_or_else(|| format!("{}:{}", self.server_host, self.server_port));
+
+        let secure = self.config.tls.is_some();
+        if let Some(proxy) = Proxy::for_server(self.config.proxy.as_deref(), &first, secure)? {
+            self.add_log(&format!("Connecting to the tunnel server through proxy {}", proxy));
+            // Latency to the proxy says nothing about the servers behind it
+            if self.servers.len() > 1 {
+                self.add_log(&format!("Not probing servers through the proxy, using {}", first));
+            }
+            return Ok(first);
+        }
+
+        if self.servers.is_empty() {
+            return Ok(first);
+        }
+
+        self.add_log(&format!("Probing {} tunnel servers...", self.servers.len()));
+        let (server_addr, latency) = tunnel::client::select_server(&self.servers).await?;
+        self.add_log(&format!(
+            "Selected server {} ({} ms)",
+            server_addr,
+            latency.as_millis()
+        ));
+
+        Ok(server_addr)
+    }
+
+    /// Warns if the custom domain doesn't resolve to the tunnel server, the
+    /// usual reason a custom domain gets no traffic
+    async fn check_domain_dns(&mut self, server_addr: &str) {
+        let Some(domain) = &self.domain else {
+            return;
+        };
+        let host = domain.split('/').next().unwrap_or(domain).to_string();
+
+        let server_ips: Vec<IpAddr> = match lookup_host(server_addr).await {
+            Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+            Err(_) => return,
+        };
+        // Nothing meaningful to compare against when testing locally
+        if server_ips.iter().all(|ip| ip.is_loopback()) {
+            return;
+        }
+
+        let domain_addrs = lookup_host((host.as_str(), 443)).await;
+        match domain_addrs {
+            Ok(addrs) => {
+                let domain_ips: Vec<IpAddr> = addrs.map(|addr| addr.ip()).collect();
+                if !domain_ips.iter().any(|ip| server_ips.contains(ip)) {
+                    self.add_log(&format!(
+                        "Warning: {} resolves to {:?}, not the tunnel server ({:?}). Check its DNS record.",
+                        host, domain_ips, server_ips
+                    ));
+                }
+            }
+            Err(e) => {
+                self.add_log(&format!(
+                    "Warning: {} does not resolve ({}). Point its DNS record at the tunnel server.",
+                    host, e
+                ));
+            }
+        }
+    }
+
     pub async fn stop_tunnel(&mut self) -> Result<()> {
-        if let Some(mut process) = self.tunnel_process.take() {
-            self.add_log("Stopping tunnel...");
+        if self.tunnel_process.is_none() && self.tunnel_task.is_none() {
+            return Ok(());
+        }
 
-            // Kill the process
-            if let Err(e) = process.kill().await {
-                self.add_log(&format!("Error stopping tunnel: {}", e));
-            } else {
-                self.add_log("Tunnel stopped");
+        self.add_log("Stopping tunnel...");
+
+        // Stop in-process forwarding, letting in-flight requests finish first
+        if let Some(mut task) = self.tunnel_task.take() {
+            if let Some(drain) = self.tunnel_drain.take() {
+                let _ = drain.send(true);
+                if timeout(self.drain_period, &mut task).await.is_err() {
+                    self.add_log("Drain period elapsed, dropping in-flight requests");
+                }
+            }
+            task.abort();
+        }
+
+        // Kill the provider process
+        let mut stopped = true;
+        if let Some(mut process) = self.tunnel_process.take() {
+            let exited = self.drain_process(&mut process).await;
+            if !exited {
+                if let Err(e) = process.kill().await {
+                    self.add_error(&format!("Error stopping tunnel: {}", e));
+                    stopped = false;
+                }
             }
+        }
+
+        if stopped {
+            self.add_log("Tunnel stopped");
+        }
+        self.audit(AuditEvent::TunnelDown {
+            reason: "stopped".to_string(),
+        });
+
+        self.tunnel_active = false;
+        self.tunnel_url = None;
+        self.connection_error = None;
+        self.tunnel_events = None;
+        self.in_flight = (0, 0);
+
+        Ok(())
+    }
+
+    /// Stops and starts the tunnel in one step, keeping the same subdomain
+    pub async fn restart_tunnel(&mut self) -> Result<()> {
+        if self.tunnel_active {
+            self.add_log("Restarting tunnel...");
+            self.stop_tunnel().await?;
+        }
+        self.start_tunnel().await
+    }
+
+    /// Average request and response sizes over the recent requests
+    pub fn average_sizes(&self) -> Option<(usize, usize)> {
+        let count = self.recent_sizes.len();
+        if count == 0 {
+            return None;
+        }
+
+        let (requests, responses) = self
+            .recent_sizes
+            .iter()
+            .fold((0, 0), |(req, res), (r, s)| (req + r, res + s));
+        Some((requests / count, responses / count))
+    }
+
+    /// Re-registers under a fresh subdomain, e.g. after the old URL was shared too widely
+    pub async fn regenerate_url(&mut self) -> Result<()> {
+        if self.provider != Provider::Builtin || self.domain.is_some() {
+            self.add_log("Only generated builtin subdomains can be regenerated");
+            return Ok(());
+        }
 
-            self.tunnel_active = false;
-            self.tunnel_url = None;
-            self.connection_error = None;
+        let was_active = self.tunnel_active;
+        if was_active {
+            self.stop_tunnel().await?;
         }
 
+        self.client_id = subdomain::generate(self.config.subdomain_style);
+        self.add_log(&format!("New subdomain: {}", self.client_id));
+
+        if was_active {
+            self.start_tunnel().await?;
+        }
         Ok(())
     }
 
+    /// Applies any events the running tunnel has reported since the last call
+    pub fn process_tunnel_events(&mut self) {
+        // Forget expired captures, closing the popup if the one shown went
+        if let Some(captures) = &mut self.captures {
+            captures.prune();
+            let count = captures.entries().len();
+            if self.mode == AppMode::ShowCapture && self.capture_selection >= count {
+                if count == 0 {
+                    self.mode = AppMode::Normal;
+                } else {
+                    self.capture_selection = count - 1;
+                }
+            }
+        }
+
+        let mut events = Vec::new();
+        if let Some(receiver) = &mut self.tunnel_events {
+            while let Ok(event) = receiver.try_recv() {
+                events.push(event);
+            }
+        }
+
+        for event in events {
+            match event {
+                TunnelEvent::Log(message) => self.add_log(&message),
+                TunnelEvent::Error(message) => self.add_error(&message),
+                TunnelEvent::Disconnected { reason } => {
+                    self.audit(AuditEvent::Disconnect {
+                        reason: reason.clone(),
+                    });
+                    self.last_disconnect_reason = Some(reason);
+                }
+                TunnelEvent::Reconnecting => {
+                    self.reconnect_count += 1;
+                    self.audit(AuditEvent::Reconnect {
+                        attempt: self.reconnect_count,
+                    });
+                }
+                TunnelEvent::Quota { remaining } => {
+                    self.quota_remaining = Some(remaining);
+                }
+                TunnelEvent::Captured {
+                    trace,
+                    request,
+                    response,
+                } => {
+                    if let Some(captures) = &mut self.captures {
+                        captures.push(trace, &request, &response);
+                    }
+                }
+                TunnelEvent::InFlight { active, queued } => {
+                    self.in_flight = (active, queued);
+                }
+                TunnelEvent::RequestCompleted {
+                    request_bytes,
+                    response_bytes,
+                    duration,
+                    trace,
+                    request_line,
+                    status,
+                } => {
+                    // Tag the line with the trace id so it can be matched
+                    // with the server's logs for the same request
+                    self.add_log(&format!(
+                        "{}{} -> {} in {} ms",
+                        trace.as_deref().map(|trace| format!("[{}] ", trace)).unwrap_or_default(),
+                        request_line.as_deref().unwrap_or("Request"),
+                        status.map_or("no response".to_string(), |status| status.to_string()),
+                        duration.as_millis()
+                    ));
+                    self.audit(AuditEvent::Request {
+                        request_bytes,
+                        response_bytes,
+                        duration_ms: duration.as_millis() as u64,
+                        trace,
+                    });
+                    self.recent_durations.push_back(duration.as_millis() as u64);
+                    if self.recent_durations.len() > RECENT_DURATIONS_WINDOW {
+                        self.recent_durations.pop_front();
+                    }
+
+                    self.request_count += 1;
+                    self.recent_sizes.push_back((request_bytes, response_bytes));
+                    if self.recent_sizes.len() > RECENT_SIZES_WINDOW {
+                        self.recent_sizes.pop_front();
+                    }
+                }
+                TunnelEvent::Ended { reason } => {
+                    self.audit(AuditEvent::TunnelDown {
+                        reason: reason.clone(),
+                    });
+                    self.in_flight = (0, 0);
+                    self.tunnel_active = false;
+                    self.tunnel_url = None;
+                    self.tunnel_task = None;
+                    self.connection_error = Some(reason);
+                }
+            }
+        }
+    }
+
+    /// Appends an event to the audit log, giving up on the log if it can't be written
+    fn audit(&mut self, event: AuditEvent) {
+        let Some(audit_log) = &mut self.audit_log else {
+            return;
+        };
+        if let Err(e) = audit_log.record(&event) {
+            self.audit_log = None;
+            self.add_error(&format!("Failed to write audit log, disabling it: {}", e));
+        }
+    }
+
     pub fn add_log(&mut self, message: &str) {
-        self.logs.push(format!(
-            "[{}] {}",
-            chrono::Local::now().format("%H:%M:%S"),
-            message
-        ));
-        if self.logs.len() > 1000 {
-            self.logs.remove(0);
+        self.push_log(LogLevel::Info, message);
+    }
+
+    pub fn add_error(&mut self, message: &str) {
+        self.push_log(LogLevel::Error, message);
+    }
+
+    fn push_log(&mut self, level: LogLevel, message: &str) {
+        if self.silent_logs && level != LogLevel::Error {
+            return;
+        }
+
+        self.logs.push(LogEntry {
+            at: chrono::Local::now(),
+            message: message.to_string(),
+        });
+        if self.logs.len() > self.log_retention {
+            let excess = self.logs.len() - self.log_retention;
+            self.logs.drain(..excess);
+        }
+
+        // Keep the view on the same lines while autoscroll is paused
+        if !self.follow_logs {
+            self.log_offset = (self.log_offset + 1).min(self.logs.len());
         }
     }
 
+    /// Switches between recording everything and recording only errors
+    pub fn toggle_silent_logs(&mut self) {
+        if self.silent_logs {
+            self.silent_logs = false;
+            self.add_log("Recording all logs");
+        } else {
+            self.add_log("Recording errors only, press 'S' to record everything again");
+            self.silent_logs = true;
+        }
+    }
+
+    /// Cycles the log pane between clock, relative and no timestamps
+    pub fn cycle_log_timestamps(&mut self) {
+        self.log_timestamps = self.log_timestamps.next();
+    }
+
+    pub fn clear_logs(&mut self) {
+        self.logs.clear();
+        self.log_offset = 0;
+        self.follow_logs = true;
+    }
+
+    /// Scrolling up pauses autoscroll so new logs don't move the view
     pub fn scroll_logs_up(&mut self) {
         if self.log_offset < self.logs.len() {
             self.log_offset += 1;
+            self.follow_logs = false;
         }
     }
 
+    /// Scrolling back down to the newest line resumes autoscroll
     pub fn scroll_logs_down(&mut self) {
         if self.log_offset > 0 {
             self.log_offset -= 1;
         }
+        if self.log_offset == 0 {
+            self.follow_logs = true;
+        }
     }
 
-    pub fn visible_logs(&self) -> Vec<&String> {
-        let start = if self.logs.len() > self.log_offset {
-            self.logs.len() - self.log_offset
-        } else {
-            0
-        };
+    /// Toggles between following the newest logs and holding the current view
+    pub fn toggle_follow_logs(&mut self) {
+        self.follow_logs = !self.follow_logs;
+        if self.follow_logs {
+            self.log_offset = 0;
+        }
+    }
+
+    /// Returns the logs that fit in `height` lines, ending `log_offset` lines
+    /// above the newest, stamped as `log_timestamps` says
+    pub fn visible_logs(&self, height: usize) -> Vec<String> {
+        let end = self.logs.len().saturating_sub(self.log_offset);
+        let start = end.saturating_sub(height);
 
-        self.logs.iter().skip(start).collect()
+        (start..end)
+            .map(|index| {
+                let entry = &self.logs[index];
+                match self.log_timestamps {
+                    LogTimestamps::Clock => format!("[{}] {}", entry.at.format("%H:%M:%S"), entry.message),
+                    LogTimestamps::Relative => {
+                        let since = index
+                            .checked_sub(1)
+                            .map_or(chrono::Duration::zero(), |previous| entry.at - self.logs[previous].at);
+                        format!(
+                            "[+{:.1}s] {}",
+                            since.num_milliseconds().max(0) as f64 / 1000.0,
+                            entry.message
+                        )
+                    }
+                    LogTimestamps::None => entry.message.clone(),
+                }
+            })
+            .collect()
     }
     
+    /// Builds a command line reproducing the current settings, leaving out
+    /// the token so it is safe to share
+    pub fn share_command(&self) -> String {
+        let mut args = vec![
+            "rshare".to_string(),
+            "--port".to_string(),
+            self.port.to_string(),
+            "--provider".to_string(),
+            self.provider.name().to_string(),
+        ];
+
+        if self.auto_port {
+            args.push("--auto-port".to_string());
+        }
+
+        if self.local_host != "localhost" {
+            args.extend(["--local-host".to_string(), shell_quote(&self.local_host)]);
+        }
+
+        if let Some(path) = &self.local_socket {
+            args.extend([
+                "--local-socket".to_string(),
+                shell_quote(&path.to_string_lossy()),
+            ]);
+        }
+
+        if let Some(host) = &self.forward_host_header {
+            args.extend(["--forward-host-header".to_string(), shell_quote(host)]);
+        }
+
+        if let Some(base_path) = &self.base_path {
+            args.extend(["--base-path".to_string(), shell_quote(base_path)]);
+        }
+
+        if let Some(tee) = &self.tee {
+            args.extend(["--tee".to_string(), shell_quote(tee)]);
+        }
+
+        if let Some(max) = self.max_concurrent {
+            args.extend(["--max-concurrent".to_string(), max.to_string()]);
+        }
+
+        let mut headers: Vec<_> = self.response_headers.iter().collect();
+        headers.sort();
+        for (name, value) in headers {
+            args.extend([
+                "--response-header".to_string(),
+                shell_quote(&format!("{}: {}", name, value)),
+            ]);
+        }
+
+        if let Some(domain) = &self.domain {
+            args.extend(["--domain".to_string(), shell_quote(domain)]);
+        }
+
+        if self.provider == Provider::Builtin {
+            if self.servers.is_empty() {
+                args.extend([
+                    "--server-host".to_string(),
+                    shell_quote(&self.server_host),
+                    "--public-port".to_string(),
+                    self.server_port.to_string(),
+                ]);
+            } else {
+                for server in &self.servers {
+                    args.extend(["--server-url".to_string(), shell_quote(server)]);
+                }
+            }
+        }
+
+        for arg in &self.provider_args {
+            args.push(shell_quote(&format!("--provider-arg={}", arg)));
+        }
+
+        args.join(" ")
+    }
+
+    pub fn log_share_command(&mut self) {
+        let command = self.share_command();
+        self.add_log(&format!("Share this setup: {}", command));
+    }
+
+    /// Writes the logs currently held in memory to a timestamped file in the
+    /// working directory and reports where it went
+    pub fn export_logs(&mut self) {
+        let path = PathBuf::from(format!(
+            "rshare-logs-{}.log",
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ));
+        let mut contents: String = self
+            .logs
+            .iter()
+            .map(|entry| format!("[{}] {}\n", entry.at.format("%H:%M:%S"), entry.message))
+            .collect();
+        if contents.is_empty() {
+            contents.push('\n');
+        }
+
+        match std::fs::write(&path, contents) {
+            Ok(()) => {
+                let path = std::fs::canonicalize(&path).unwrap_or(path);
+                self.add_log(&format!("Exported {} log lines to {}", self.logs.len(), path.display()));
+            }
+            Err(e) => self.add_error(&format!("Failed to export logs to {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Copies the tunnel URL with the configured clipboard backend, falling
+    /// back to logging it for a manual copy when that isn't possible
+    pub fn copy_url(&mut self) {
+        let Some(url) = self.tunnel_url.clone() else {
+            self.add_log("No tunnel URL yet, press 's' to start the tunnel");
+            return;
+        };
+
+        let backend = self.clipboard;
+        if backend == ClipboardBackend::Print {
+            self.add_log(&format!("Copy this URL: {}", url));
+            return;
+        }
+        match clipboard::copy(backend, &url) {
+            Ok(()) => self.add_log(&format!("Copied {} to the clipboard ({})", url, backend.name())),
+            Err(e) => {
+                self.add_error(&format!("Failed to copy with {}: {:#}", backend.name(), e));
+                self.add_log(&format!("Copy this URL: {}", url));
+            }
+        }
+    }
+
     pub fn enter_config_port_mode(&mut self) {
         self.mode = AppMode::ConfigPort;
-        self.input_buffer = self.port.to_string();
+        // Offer the fix for a clashing port rather than the current value
+        self.input_buffer = self.port_conflict_fix().unwrap_or(self.port).to_string();
     }
     
     pub fn enter_config_server_port_mode(&mut self) {
@@ -160,6 +1079,105 @@ impl App {
         self.input_buffer = self.server_port.to_string();
     }
     
+    /// Opens the full tunnel URL in a popup, returning whether there was one to show
+    pub fn enter_show_url_mode(&mut self) -> bool {
+        if self.tunnel_url.is_none() {
+            self.add_log("No tunnel URL yet, press 's' to start the tunnel");
+            return false;
+        }
+
+        self.mode = AppMode::ShowUrl;
+        true
+    }
+
+    /// Opens the most recent captured request and response
+    pub fn enter_show_capture_mode(&mut self) {
+        let Some(captures) = &mut self.captures else {
+            self.add_log("Bodies aren't being captured, start with --capture-bodies to keep them");
+            return;
+        };
+        captures.prune();
+        if captures.entries().is_empty() {
+            self.add_log("No requests captured yet");
+            return;
+        }
+
+        self.capture_selection = 0;
+        self.capture_scroll = 0;
+        self.mode = AppMode::ShowCapture;
+    }
+
+    pub fn select_older_capture(&mut self) {
+        let count = self.captures.as_ref().map_or(0, |captures| captures.entries().len());
+        if self.capture_selection + 1 < count {
+            self.capture_selection += 1;
+            self.capture_scroll = 0;
+        }
+    }
+
+    pub fn select_newer_capture(&mut self) {
+        if self.capture_selection > 0 {
+            self.capture_selection -= 1;
+            self.capture_scroll = 0;
+        }
+    }
+
+    pub fn enter_pick_provider_mode(&mut self) {
+        self.mode = AppMode::PickProvider;
+        // Checked once here rather than on every redraw
+        self.provider_choices = Provider::ALL
+            .iter()
+            .map(|provider| (*provider, provider.is_installed()))
+            .collect();
+        self.provider_selection = self
+            .provider_choices
+            .iter()
+            .position(|(provider, _)| *provider == self.provider)
+            .unwrap_or(0);
+    }
+
+    pub fn select_previous_provider(&mut self) {
+        self.provider_selection = self.provider_selection.saturating_sub(1);
+    }
+
+    pub fn select_next_provider(&mut self) {
+        if self.provider_selection + 1 < self.provider_choices.len() {
+            self.provider_selection += 1;
+        }
+    }
+
+    /// Switches to the highlighted provider and saves it as the default
+    pub fn confirm_provider(&mut self) -> Result<()> {
+        if let Some((provider, _)) = self.provider_choices.get(self.provider_selection) {
+            self.set_provider(*provider)?;
+        }
+
+        self.mode = AppMode::Normal;
+        Ok(())
+    }
+
+    /// Moves on to the next provider, only while the tunnel is stopped
+    pub fn cycle_provider(&mut self) -> Result<()> {
+        if self.tunnel_active {
+            self.add_log("Stop the tunnel before switching providers");
+            return Ok(());
+        }
+
+        let index = Provider::ALL
+            .iter()
+            .position(|provider| *provider == self.provider)
+            .unwrap_or(0);
+        self.set_provider(Provider::ALL[(index + 1) % Provider::ALL.len()])
+    }
+
+    fn set_provider(&mut self, provider: Provider) -> Result<()> {
+        self.provider = provider;
+        self.config.provider = provider;
+        self.add_log(&format!("Provider set to: {}", provider.name()));
+        self.check_provider_installed();
+        Config::update(|config| config.provider = provider)
+    }
+
     pub fn exit_config_mode(&mut self) {
         self.mode = AppMode::Normal;
         self.input_buffer.clear();
@@ -173,7 +1191,8 @@ impl App {
                         self.port = port;
                         self.config.port = port;
                         self.add_log(&format!("Port updated to: {}", port));
-                        self.config.save()?;
+                        Config::update(|config| config.port = port)?;
+                        self.check_port_conflict();
                     } else {
                         self.add_log("Invalid port: must be greater than 0");
                     }
@@ -187,7 +1206,8 @@ impl App {
                         self.server_port = port;
                         self.config.server_port = port;
                         self.add_log(&format!("Server port updated to: {}", port));
-                        self.config.save()?;
+                        Config::update(|config| config.server_port = port)?;
+                        self.check_port_conflict();
                     } else {
                         self.add_log("Invalid port: must be greater than 0");
                     }
@@ -214,4 +1234,18 @@ impl App {
             self.input_buffer.pop();
         }
     }
-}
\ No newline at end of file
+}
+
+/// Quotes a value for a POSIX shell if it contains anything unusual
+fn shell_quote(value: &str) -> String {
+    let safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:/=@,".contains(c));
+
+    if safe {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
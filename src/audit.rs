@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// A tunnel lifecycle event, written as one JSON object per line
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    TunnelUp {
+        url: String,
+        provider: String,
+        local: String,
+    },
+    /// The tunnel couldn't be started
+    TunnelError { error: String },
+    TunnelDown { reason: String },
+    Disconnect { reason: String },
+    Reconnect { attempt: u32 },
+    /// A forwarded request finished
+    Request {
+        request_bytes: usize,
+        response_bytes: usize,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        trace: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    ts: String,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Appends machine-readable events to a file, alongside the human log pane
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    pub fn record(&mut self, event: &AuditEvent) -> Result<()> {
+        let record = AuditRecord {
+            ts: chrono::Utc::now().to_rfc3339(),
+            event,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
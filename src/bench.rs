@@ -0,0 +1,106 @@
+use anyhow::{bail, Context, Result};
+use http_body_util::BodyExt;
+use hyper::Uri;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::http_client::{self, Connection};
+
+/// Outcome of one request: its status, or `None` if it failed outright
+struct Sample {
+    status: Option<u16>,
+    latency: Duration,
+}
+
+/// Sends `requests` GET requests to `url`, `concurrency` at a time, and prints
+/// throughput and latency percentiles
+pub async fn run(url: &str, requests: usize, concurrency: usize) -> Result<()> {
+    if requests == 0 || concurrency == 0 {
+        bail!("--requests and --concurrency must be at least 1");
+    }
+    let uri: Uri = url.parse().with_context(|| format!("Invalid URL: {}", url))?;
+
+    let next = Arc::new(AtomicUsize::new(0));
+
+    println!(
+        "Sending {} requests to {} with concurrency {}",
+        requests, uri, concurrency
+    );
+    let started = Instant::now();
+
+    let workers: Vec<_> = (0..concurrency.min(requests))
+        .map(|_| {
+            let uri = uri.clone();
+            let next = next.clone();
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                let mut connection = None;
+                while next.fetch_add(1, Ordering::Relaxed) < requests {
+                    samples.push(send(&mut connection, &uri).await);
+                }
+                samples
+            })
+        })
+        .collect();
+
+    let mut samples = Vec::with_capacity(requests);
+    for worker in workers {
+        samples.extend(worker.await?);
+    }
+    let elapsed = started.elapsed();
+
+    report(&mut samples, elapsed);
+    Ok(())
+}
+
+/// Times a single request, reading the whole response body. Each worker keeps
+/// its connection open between requests and reconnects once it closes.
+async fn send(connection: &mut Option<Connection>, uri: &Uri) -> Sample {
+    let started = Instant::now();
+    let status = request(connection, uri).await.ok();
+    Sample {
+        status,
+        latency: started.elapsed(),
+    }
+}
+
+async fn request(connection: &mut Option<Connection>, uri: &Uri) -> Result<u16> {
+    let sender = match connection {
+        Some(sender) if !sender.is_closed() => sender,
+        _ => connection.insert(http_client::connect(uri).await?),
+    };
+    sender.ready().await?;
+    let response = sender.send_request(http_client::get(uri)?).await?;
+    let status = response.status().as_u16();
+    response.into_body().collect().await?;
+    Ok(status)
+}
+
+fn report(samples: &mut [Sample], elapsed: Duration) {
+    let succeeded = samples
+        .iter()
+        .filter(|sample| sample.status.is_some_and(|status| status < 400))
+        .count();
+    let errored = samples.iter().filter(|sample| sample.status.is_none()).count();
+
+    println!();
+    println!("Completed in {:.2}s", elapsed.as_secs_f64());
+    println!(
+        "Throughput: {:.1} requests/s",
+        samples.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Responses: {} ok, {} error status, {} failed to connect or read",
+        succeeded,
+        samples.len() - succeeded - errored,
+        errored
+    );
+
+    samples.sort_by_key(|sample| sample.latency);
+    println!("Latency:");
+    for (label, quantile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+        let index = ((samples.len() as f64 * quantile).ceil() as usize).clamp(1, samples.len()) - 1;
+        println!("  {}: {} ms", label, samples[index].latency.as_millis());
+    }
+}
@@ -0,0 +1,205 @@
+use regex::Regex;
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::config::CaptureConfig;
+use crate::tunnel::http;
+
+/// A request and its response as they crossed the tunnel, already redacted
+pub struct Capture {
+    pub captured_at: Instant,
+    pub trace: Option<String>,
+    pub request: String,
+    pub response: String,
+}
+
+/// The bodies of the most recent requests, kept for a limited time so
+/// payloads can be looked at after the fact without holding on to them forever
+pub struct CaptureStore {
+    max_entries: usize,
+    max_body_bytes: usize,
+    ttl: Duration,
+    /// Oldest first
+    entries: VecDeque<Capture>,
+}
+
+impl CaptureStore {
+    pub fn new(config: &CaptureConfig) -> Self {
+        Self {
+            max_entries: config.max_entries.max(1),
+            max_body_bytes: config.max_body_bytes,
+            ttl: Duration::from_secs(config.ttl_secs),
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Bytes of each direction worth holding on to: the head plus at most
+    /// `max_body_bytes` of body
+    pub fn byte_limit(&self) -> usize {
+        self.max_body_bytes + MAX_HEAD_BYTES
+    }
+
+    pub fn push(&mut self, trace: Option<String>, request: &[u8], response: &[u8]) {
+        self.prune();
+        if self.entries.len() == self.max_entries {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Capture {
+            captured_at: Instant::now(),
+            trace,
+            request: render(request, self.max_body_bytes),
+            response: render(response, self.max_body_bytes),
+        });
+    }
+
+    /// Captures kept as of the last prune, oldest first
+    pub fn entries(&self) -> &VecDeque<Capture> {
+        &self.entries
+    }
+
+    /// Drops captures older than the TTL
+    pub fn prune(&mut self) {
+        while self
+            .entries
+            .front()
+            .is_some_and(|capture| capture.captured_at.elapsed() >= self.ttl)
+        {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Request and response heads longer than this are cut off
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// Header names whose values are never shown
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+];
+
+/// Turns a raw HTTP message into text for display, with credentials redacted
+/// and the body cut off at `max_body_bytes`
+fn render(raw: &[u8], max_body_bytes: usize) -> String {
+    let Some(head_len) = http::head_len(raw) else {
+        return format!("(incomplete, {} bytes)", raw.len());
+    };
+    let head = String::from_utf8_lossy(&raw[..head_len]);
+    let mut text: String = head
+        .trim_end()
+        .split("\r\n")
+        .map(|line| match line.split_once(':') {
+            Some((name, _)) if SECRET_HEADERS.contains(&name.trim().to_ascii_lowercase().as_str()) => {
+                format!("{}: [redacted]", name)
+            }
+            // The request target, `Referer` and `Location` can carry
+            // credentials in their query strings
+            _ => redact_query(line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let body = &raw[head_len..];
+    if body.is_empty() {
+        return text;
+    }
+    text.push_str("\n\n");
+
+    let shown = &body[..body.len().min(max_body_bytes)];
+    match std::str::from_utf8(shown) {
+        Ok(body_text) => text.push_str(&redact_body(body_text)),
+        Err(e) if e.error_len().is_none() => {
+            // Cut off in the middle of a character
+            let body_text = std::str::from_utf8(&shown[..e.valid_up_to()]).unwrap();
+            text.push_str(&redact_body(body_text));
+        }
+        Err(_) => text.push_str(&format!("({} bytes of binary data)", body.len())),
+    }
+    if shown.len() < body.len() {
+        text.push_str(&format!("\n... ({} more bytes not kept)", body.len() - shown.len()));
+    }
+    text
+}
+
+/// Names of fields whose values are never shown
+const SECRET_FIELDS: &str =
+    "password|passwd|secret|client_secret|token|access_token|refresh_token|id_token|api_key|apikey";
+
+/// Blanks out values of secret-looking query parameters in a line of the
+/// head, including OAuth authorization codes
+fn redact_query(line: &str) -> String {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| {
+        Regex::new(&format!(r"(?i)([?&](?:{}|code)=)[^&#\s]*", SECRET_FIELDS)).unwrap()
+    });
+    pattern.replace_all(line, "$1[redacted]").into_owned()
+}
+
+/// Blanks out values of secret-looking fields in JSON and form bodies
+fn redact_body(body: &str) -> String {
+    static PATTERNS: OnceLock<(Regex, Regex)> = OnceLock::new();
+    let (json, form) = PATTERNS.get_or_init(|| {
+        (
+            Regex::new(&format!(r#"(?i)("(?:{})"\s*:\s*)"(?:[^"\\]|\\.)*""#, SECRET_FIELDS)).unwrap(),
+            Regex::new(&format!(r"(?i)\b((?:{})=)[^&\s]*", SECRET_FIELDS)).unwrap(),
+        )
+    });
+    let body = json.replace_all(body, r#"$1"[redacted]""#);
+    form.replace_all(&body, "$1[redacted]").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secret_query_parameters() {
+        let request = b"GET /callback?code=abc123&state=xyz&access_token=t0k HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Referer: https://example.com/login?next=%2F&password=hunter2\r\n\r\n";
+        let text = render(request, 1024);
+        assert!(text.contains("GET /callback?code=[redacted]&state=xyz&access_token=[redacted] HTTP/1.1"));
+        assert!(text.contains("Referer: https://example.com/login?next=%2F&password=[redacted]"));
+        assert!(!text.contains("abc123") && !text.contains("t0k") && !text.contains("hunter2"));
+    }
+
+    #[test]
+    fn redacts_secret_headers() {
+        let request = b"GET / HTTP/1.1\r\nAuthorization: Bearer s3cret\r\nCookie: session=abc\r\nAccept: */*\r\n\r\n";
+        let text = render(request, 1024);
+        assert!(text.contains("Authorization: [redacted]"));
+        assert!(text.contains("Cookie: [redacted]"));
+        assert!(text.contains("Accept: */*"));
+        assert!(!text.contains("s3cret") && !text.contains("session=abc"));
+    }
+
+    #[test]
+    fn redacts_secret_json_fields() {
+        let body = r#"{"user": "ana", "password": "hunter\"2", "Client_Secret" : "xyz"}"#;
+        assert_eq!(
+            redact_body(body),
+            r#"{"user": "ana", "password": "[redacted]", "Client_Secret" : "[redacted]"}"#
+        );
+    }
+
+    #[test]
+    fn redacts_secret_form_fields() {
+        assert_eq!(
+            redact_body("user=ana&password=hunter2&remember=1"),
+            "user=ana&password=[redacted]&remember=1"
+        );
+        // Only whole field names are secret
+        assert_eq!(redact_body("passwordless=1"), "passwordless=1");
+    }
+
+    #[test]
+    fn redacts_bodies_as_rendered() {
+        let request = b"POST /login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"token\": \"abc\"}";
+        assert!(render(request, 1024).ends_with("\n\n{\"token\": \"[redacted]\"}"));
+    }
+}
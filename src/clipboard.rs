@@ -0,0 +1,115 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::tunnel::is_on_path;
+
+/// Where copied text goes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipboardBackend {
+    /// Pick the best backend available in this environment
+    #[default]
+    Auto,
+    /// The OSC 52 terminal escape sequence, which also works over SSH
+    Osc52,
+    /// `wl-copy`, for Wayland
+    WlCopy,
+    /// `xclip`, for X11
+    Xclip,
+    /// `pbcopy`, on macOS
+    Pbcopy,
+    /// `clip.exe`, the Windows clipboard from WSL
+    ClipExe,
+    /// Don't touch a clipboard, just log the text to copy by hand
+    Print,
+}
+
+impl ClipboardBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClipboardBackend::Auto => "auto",
+            ClipboardBackend::Osc52 => "osc52",
+            ClipboardBackend::WlCopy => "wl-copy",
+            ClipboardBackend::Xclip => "xclip",
+            ClipboardBackend::Pbcopy => "pbcopy",
+            ClipboardBackend::ClipExe => "clip-exe",
+            ClipboardBackend::Print => "print",
+        }
+    }
+
+    /// Resolves `Auto` to a concrete backend for this environment. Over SSH the
+    /// terminal on the user's machine is the only clipboard that matters, so
+    /// OSC 52 wins; locally a clipboard tool is preferred when one is installed.
+    /// This searches the PATH, so it is done once at startup.
+    pub fn resolve(self) -> Self {
+        if self != ClipboardBackend::Auto {
+            return self;
+        }
+        if env::var_os("SSH_TTY").is_some() || env::var_os("SSH_CONNECTION").is_some() {
+            return ClipboardBackend::Osc52;
+        }
+        if env::var_os("WSL_DISTRO_NAME").is_some() && is_on_path("clip.exe") {
+            return ClipboardBackend::ClipExe;
+        }
+        if env::var_os("WAYLAND_DISPLAY").is_some() && is_on_path("wl-copy") {
+            return ClipboardBackend::WlCopy;
+        }
+        if env::var_os("DISPLAY").is_some() && is_on_path("xclip") {
+            return ClipboardBackend::Xclip;
+        }
+        if cfg!(target_os = "macos") && is_on_path("pbcopy") {
+            return ClipboardBackend::Pbcopy;
+        }
+        if env::var_os("TERM").is_some_and(|term| term != "dumb") {
+            return ClipboardBackend::Osc52;
+        }
+        ClipboardBackend::Print
+    }
+}
+
+/// Puts `text` on the clipboard with a concrete (already resolved) backend.
+/// `Print` does nothing here; the caller shows the text instead.
+pub fn copy(backend: ClipboardBackend, text: &str) -> Result<()> {
+    match backend {
+        ClipboardBackend::Auto => copy(backend.resolve(), text),
+        ClipboardBackend::Osc52 => {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+            let mut stdout = std::io::stdout();
+            write!(stdout, "\x1b]52;c;{}\x07", encoded)?;
+            stdout.flush()?;
+            Ok(())
+        }
+        ClipboardBackend::WlCopy => pipe_to("wl-copy", &[], text),
+        ClipboardBackend::Xclip => pipe_to("xclip", &["-selection", "clipboard"], text),
+        ClipboardBackend::Pbcopy => pipe_to("pbcopy", &[], text),
+        ClipboardBackend::ClipExe => pipe_to("clip.exe", &[], text),
+        ClipboardBackend::Print => Ok(()),
+    }
+}
+
+fn pipe_to(program: &str, args: &[&str], text: &str) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(text.as_bytes())
+        .with_context(|| format!("Failed to write to {}", program))?;
+
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("{} exited with {}", program, status);
+    }
+    Ok(())
+}
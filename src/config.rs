@@ -1,27 +1,325 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
+use crate::app::LogTimestamps;
+use crate::clipboard::ClipboardBackend;
+use crate::subdomain::SubdomainStyle;
+use crate::tunnel::Provider;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub port: u16,
+    /// Host running the tunnel server
+    #[serde(default = "default_server_host")]
+    pub server_host: String,
     pub server_port: u16,
     pub domain: Option<String>,
+    #[serde(default)]
+    pub provider: Provider,
+    /// Extra arguments appended to the ngrok/cloudflared/localtunnel command
+    #[serde(default)]
+    pub provider_args: Vec<String>,
+    /// Token identifying this client to the server, used to claim reserved subdomains
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Candidate tunnel servers (`host:port`), the fastest to respond is used
+    #[serde(default)]
+    pub servers: Vec<String>,
+    /// Look for a service on common dev server ports at startup, falling back to `port`
+    #[serde(default)]
+    pub auto_port: bool,
+    /// Host the local service listens on, an IPv4 or IPv6 address or a name
+    /// whose addresses are tried in turn
+    #[serde(default = "default_local_host")]
+    pub local_host: String,
+    /// Unix domain socket to forward to instead of `port`
+    #[serde(default)]
+    pub local_socket: Option<PathBuf>,
+    /// Host header sent to the local service instead of the public host
+    #[serde(default)]
+    pub forward_host_header: Option<String>,
+    /// Path the local app is mounted under, prepended to forwarded request paths
+    #[serde(default)]
+    pub base_path: Option<String>,
+    /// Headers added to every response served through the tunnel, e.g. `Cache-Control: no-store`
+    #[serde(default)]
+    pub response_headers: HashMap<String, String>,
+    /// How the subdomain is generated when no domain is set
+    #[serde(default)]
+    pub subdomain_style: SubdomainStyle,
+    /// Warn at startup if `domain` doesn't resolve to the tunnel server
+    #[serde(default = "default_check_dns")]
+    pub check_dns: bool,
+    /// Give up on the first disconnect instead of reconnecting
+    #[serde(default)]
+    pub no_reconnect: bool,
+    /// Reconnect attempts after a disconnect before giving up, 0 for no limit
+    #[serde(default)]
+    pub max_reconnect_attempts: u32,
+    /// Leave mouse events to the terminal so its native selection keeps working
+    #[serde(default)]
+    pub no_mouse: bool,
+    /// Where `c` copies the tunnel URL to
+    #[serde(default)]
+    pub clipboard: ClipboardBackend,
+    /// Number of log lines kept in memory
+    #[serde(default = "default_log_retention")]
+    pub log_retention: usize,
+    /// Only record errors in the log pane, e.g. to keep the screen clean during a demo
+    #[serde(default)]
+    pub silent_logs: bool,
+    /// How log lines are stamped: `clock`, `relative` to the line before, or `none`
+    #[serde(default)]
+    pub log_timestamps: LogTimestamps,
+    /// Look for a newer rshare release at startup (off unless asked for)
+    #[serde(default)]
+    pub update_check: bool,
+    /// Seconds in-flight requests get to finish when the tunnel is stopped
+    #[serde(default = "default_drain_secs")]
+    pub drain_secs: u64,
+    /// Most requests forwarded to the local service at once, the rest are queued
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    /// Address (`host:port`) sent a copy of every tunneled connection's traffic
+    #[serde(default)]
+    pub tee: Option<String>,
+    /// File tunnel lifecycle events are appended to as JSON lines
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    /// Keep recent request and response bodies in memory to look at with `b`
+    #[serde(default)]
+    pub capture: Option<CaptureConfig>,
+    /// Mutual TLS to the tunnel server; plain WebSocket without it
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// HTTP or SOCKS5 proxy URL for the connection to the tunnel server
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Open the connection carrying request data only when a request
+    /// arrives, and let the server close it again once the tunnel is idle
+    #[serde(default)]
+    pub lazy_connect: bool,
+    #[serde(default)]
+    pub server: ServerConfig,
+    /// Project-local settings layered over the global file, if any were found
+    #[serde(skip)]
+    pub project: Option<ProjectConfig>,
+}
+
+/// Name of the project-local config, looked for in the working directory and its parents
+const PROJECT_CONFIG_FILE: &str = ".rshare.toml";
+
+/// Per-project overrides read from `.rshare.toml`. Only the fields that are
+/// set replace the global values.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ProjectConfig {
+    pub port: Option<u16>,
+    pub domain: Option<String>,
+    pub provider: Option<Provider>,
+    pub provider_args: Option<Vec<String>>,
+    pub server_host: Option<String>,
+    pub server_port: Option<u16>,
+    pub servers: Option<Vec<String>>,
+    pub local_socket: Option<PathBuf>,
+    pub forward_host_header: Option<String>,
+    pub base_path: Option<String>,
+    pub response_headers: Option<HashMap<String, String>>,
+}
+
+/// Settings only used when running in server mode
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Page served when no tunnel matches a request, either inline HTML or a path to an HTML file
+    pub landing_page: Option<String>,
+    /// URL to redirect unmatched requests to, takes precedence over `landing_page`
+    pub landing_redirect: Option<String>,
+    /// Reject new clients, e.g. while draining before a restart
+    pub maintenance: bool,
+    /// Maximum number of connected clients
+    pub max_clients: Option<usize>,
+    /// Seconds rejected clients should wait before retrying
+    pub retry_after: Option<u64>,
+    /// JSON file mapping client tokens to the domain reserved for them
+    pub reservations: Option<PathBuf>,
+    /// Only accept clients whose token has a reserved domain, instead of
+    /// handing anonymous clients a subdomain named after their client id
+    pub require_reservation: bool,
+    /// CORS headers to add to forwarded responses
+    pub cors: Option<CorsConfig>,
+    /// Bytes each client may forward per session before being disconnected
+    pub traffic_cap: Option<u64>,
+    /// Request bodies larger than this many bytes are spooled to a temporary
+    /// file instead of being held in memory
+    pub spool_threshold: Option<u64>,
+    /// Bearer token required by the `/admin/` endpoints, which are off without one
+    pub admin_token: Option<String>,
+    /// Token required of `CONNECT` requests in `Proxy-Authorization`, which
+    /// are refused without one
+    pub connect_token: Option<String>,
+    /// Headers added to every request forwarded to a client, e.g. `Via: rshare/{version}`
+    /// so local services can tell tunnel traffic apart
+    pub request_headers: HashMap<String, String>,
+    /// Require clients to connect over mutual TLS
+    pub tls: Option<TlsConfig>,
+    /// Regexes for request paths answered with 403 instead of being forwarded,
+    /// e.g. `^/\.env` to turn away scanners
+    pub block_paths: Vec<String>,
+    /// Paths such as `/healthz` answered with 200 on every tunnel without
+    /// being forwarded, logged or counted
+    pub probe_paths: Vec<String>,
+    /// Briefly cache identical GET responses so a crowd doesn't hit the local service
+    pub response_cache: Option<ResponseCacheConfig>,
+    /// Replay GET and HEAD requests cut off by a client disconnect once the
+    /// client reconnects, as long as the response timeout hasn't passed
+    pub retry_on_reconnect: bool,
+    /// Replace a missing or `application/octet-stream` Content-Type with one
+    /// guessed from the request path's extension
+    pub fix_mime_types: bool,
+    /// Messages queued for each client's WebSocket before senders wait, 100 if unset
+    pub client_queue_size: Option<usize>,
+    /// Seconds a client may go without sending a keep-alive before it is
+    /// disconnected and its subdomain freed
+    pub heartbeat_timeout: Option<u64>,
+    /// Most headers a public request may carry, 100 if unset
+    pub max_header_count: Option<usize>,
+    /// Most bytes a public request's headers may add up to, 32KB if unset
+    pub max_header_bytes: Option<usize>,
+}
+
+/// Certificates for mutual TLS between client and server. Each side presents
+/// `cert` and only accepts a peer whose certificate was issued by `ca`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// PEM certificate chain this side presents
+    pub cert: PathBuf,
+    /// PEM private key for `cert`
+    pub key: PathBuf,
+    /// PEM CA certificate the other side's certificate must chain to
+    pub ca: PathBuf,
+    /// Name the server certificate is checked against, defaults to the server host
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+/// Cross-origin access granted on behalf of tunneled services
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to make requests, `*` allows any
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed in preflight responses, defaults to the common ones
+    pub allowed_methods: Vec<String>,
+    /// Headers allowed in preflight responses, defaults to whatever was requested
+    pub allowed_headers: Vec<String>,
+}
+
+/// Short-lived caching of repeated GET responses, per client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ResponseCacheConfig {
+    /// Responses kept per client, the least recently used are dropped first
+    pub max_entries: usize,
+    /// Seconds a response is reused before the local service is asked again
+    pub ttl_secs: u64,
+}
+
+/// In-memory retention of recent request and response bodies for debugging
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Requests kept, the oldest are dropped first
+    pub max_entries: usize,
+    /// Bytes of each request and response body kept, the rest is cut off
+    pub max_body_bytes: usize,
+    /// Seconds a capture is kept before it is forgotten
+    pub ttl_secs: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 20,
+            max_body_bytes: 64 * 1024,
+            ttl_secs: 300,
+        }
+    }
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 100,
+            ttl_secs: 5,
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             port: 8080,
+            server_host: default_server_host(),
             server_port: 8000,
             domain: None,
+            provider: Provider::default(),
+            provider_args: Vec::new(),
+            servers: Vec::new(),
+            token: None,
+            auto_port: false,
+            local_host: default_local_host(),
+            local_socket: None,
+            forward_host_header: None,
+            base_path: None,
+            response_headers: HashMap::new(),
+            subdomain_style: SubdomainStyle::default(),
+            check_dns: default_check_dns(),
+            no_reconnect: false,
+            max_reconnect_attempts: 0,
+            no_mouse: false,
+            clipboard: ClipboardBackend::default(),
+            log_retention: default_log_retention(),
+            silent_logs: false,
+            log_timestamps: LogTimestamps::default(),
+            update_check: false,
+            drain_secs: default_drain_secs(),
+            max_concurrent: None,
+            tee: None,
+            audit_log: None,
+            capture: None,
+            tls: None,
+            proxy: None,
+            lazy_connect: false,
+            server: ServerConfig::default(),
+            project: None,
         }
     }
 }
 
 impl Config {
+    /// Whether a config file has been written yet, i.e. this isn't the first run
+    pub fn exists() -> bool {
+        get_config_path().map(|path| path.exists()).unwrap_or(false)
+    }
+
+    /// Loads the global config with any project-local overrides applied
     pub fn load() -> Result<Self> {
+        let mut config = Self::load_global()?;
+
+        // Let the current project override the global settings
+        if let Some(project) = ProjectConfig::discover()? {
+            project.apply(&mut config);
+            config.project = Some(project);
+        }
+
+        Ok(config)
+    }
+
+    fn load_global() -> Result<Self> {
         let config_path = get_config_path()?;
         
         // Check if config file exists
@@ -49,14 +347,20 @@ impl Config {
             .context("Failed to parse config file")
     }
     
-    pub fn save(&self) -> Result<()> {
+    /// Writes one change to the global config file. The change is made to
+    /// the file's own values rather than this run's, so command line flags
+    /// and project overrides in effect are never written back.
+    pub fn update(change: impl FnOnce(&mut Config)) -> Result<()> {
         let config_path = get_config_path()?;
+        let mut global = Self::load_global()?;
+        change(&mut global);
+
         let config_dir = config_path.parent().unwrap();
         
         fs::create_dir_all(config_dir)
             .context("Failed to create config directory")?;
         
-        let config_str = serde_json::to_string_pretty(self)
+        let config_str = serde_json::to_string_pretty(&global)
             .context("Failed to serialize config")?;
         
         fs::write(&config_path, config_str)
@@ -66,11 +370,138 @@ impl Config {
     }
 }
 
-fn get_config_path() -> Result<PathBuf> {
-    let home_dir = dirs::home_dir()
-        .context("Failed to determine home directory")?;
-    
-    let config_dir = home_dir.join(".config").join("rshare");
+impl ProjectConfig {
+    /// Finds and parses the nearest `.rshare.toml`, walking up from the working directory
+    pub fn discover() -> Result<Option<Self>> {
+        let cwd = std::env::current_dir().context("Failed to determine working directory")?;
+        let Some(path) = cwd
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+
+        let config_str = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let project = toml::from_str(&config_str)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        Ok(Some(project))
+    }
+
+    /// Overrides `config` with every value set here
+    fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(domain) = &self.domain {
+            config.domain = Some(domain.clone());
+        }
+        if let Some(provider) = self.provider {
+            config.provider = provider;
+        }
+        if let Some(provider_args) = &self.provider_args {
+            config.provider_args = provider_args.clone();
+        }
+        if let Some(server_host) = &self.server_host {
+            config.server_host = server_host.clone();
+        }
+        if let Some(server_port) = self.server_port {
+            config.server_port = server_port;
+        }
+        if let Some(servers) = &self.servers {
+            config.servers = servers.clone();
+        }
+        if let Some(local_socket) = &self.local_socket {
+            config.local_socket = Some(local_socket.clone());
+        }
+        if let Some(host) = &self.forward_host_header {
+            config.forward_host_header = Some(host.clone());
+        }
+        if let Some(base_path) = &self.base_path {
+            config.base_path = Some(base_path.clone());
+        }
+        if let Some(headers) = &self.response_headers {
+            config.response_headers = headers.clone();
+        }
+    }
+
+}
+
+impl ServerConfig {
+    /// Resolves `landing_page` to its HTML, reading it from disk if it names a file
+    pub fn landing_page_html(&self) -> Result<Option<String>> {
+        let Some(page) = &self.landing_page else {
+            return Ok(None);
+        };
+
+        let path = PathBuf::from(page);
+        if path.is_file() {
+            let html = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read landing page {}", path.display()))?;
+            return Ok(Some(html));
+        }
+
+        Ok(Some(page.clone()))
+    }
+
+    /// Reads the token to reserved domain map, if a reservations file is configured
+    pub fn load_reservations(&self) -> Result<HashMap<String, String>> {
+        let Some(path) = &self.reservations else {
+            return Ok(HashMap::new());
+        };
+
+        let reservations_str = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read reservations file {}", path.display()))?;
+
+        serde_json::from_str(&reservations_str).context("Failed to parse reservations file")
+    }
+}
+
+fn default_server_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_local_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_check_dns() -> bool {
+    true
+}
+
+fn default_log_retention() -> usize {
+    1000
+}
+
+fn default_drain_secs() -> u64 {
+    5
+}
+
+/// Resolves the config file, preferring `$XDG_CONFIG_HOME/rshare` when it is
+/// set, then an existing `~/.config/rshare`, then the platform's config directory
+pub fn get_config_path() -> Result<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+    {
+        Some(xdg_dir) => xdg_dir.join("rshare"),
+        None => {
+            let home_dir = dirs::home_dir()
+                .context("Failed to determine home directory")?;
+            let legacy_dir = home_dir.join(".config").join("rshare");
+
+            // Keep using the original location if a config was already saved there
+            if legacy_dir.join("config.json").exists() {
+                legacy_dir
+            } else {
+                dirs::config_dir()
+                    .map(|dir| dir.join("rshare"))
+                    .unwrap_or(legacy_dir)
+            }
+        }
+    };
     let config_file = config_dir.join("config.json");
     
     Ok(config_file)
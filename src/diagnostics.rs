@@ -0,0 +1,204 @@
+use anyhow::Result;
+use std::fs;
+use std::io::IsTerminal;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::config::{self, Config};
+use crate::tunnel::client::{strip_brackets, LocalTarget};
+use crate::tunnel::Provider;
+
+/// How long to wait when checking whether something accepts connections
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Outcome of a single diagnostic, with a hint on how to fix a failure
+struct Check {
+    name: String,
+    passed: bool,
+    detail: String,
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            passed: true,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn fail(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            passed: false,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Runs every check against `config`, printing a checklist. Fails if any check did.
+pub async fn run(config: &Config) -> Result<()> {
+    let mut checks = vec![check_home_dir(), check_config_file()];
+    checks.extend(check_providers(config.provider));
+    checks.push(check_local_service(config).await);
+    if config.provider == Provider::Builtin {
+        checks.push(check_tunnel_server(config).await);
+    }
+    checks.push(check_terminal());
+
+    for check in &checks {
+        let mark = if check.passed { "ok  " } else { "FAIL" };
+        println!("[{}] {}: {}", mark, check.name, check.detail);
+        if let Some(hint) = &check.hint {
+            println!("       -> {}", hint);
+        }
+    }
+
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} of {} checks failed", failed, checks.len()));
+    }
+
+    println!("All checks passed");
+    Ok(())
+}
+
+fn check_home_dir() -> Check {
+    match dirs::home_dir() {
+        Some(home) => Check::pass("Home directory", home.display().to_string()),
+        None => Check::fail(
+            "Home directory",
+            "could not be determined",
+            "Set the HOME environment variable",
+        ),
+    }
+}
+
+fn check_config_file() -> Check {
+    let path = match config::get_config_path() {
+        Ok(path) => path,
+        Err(e) => return Check::fail("Config file", e.to_string(), "Set the HOME environment variable"),
+    };
+
+    if !path.exists() {
+        return Check::pass(
+            "Config file",
+            format!("{} not created yet, defaults will be used", path.display()),
+        );
+    }
+
+    match fs::read_to_string(&path) {
+        Ok(contents) => match serde_json::from_str::<Config>(&contents) {
+            Ok(_) => Check::pass("Config file", path.display().to_string()),
+            Err(e) => Check::fail(
+                "Config file",
+                format!("{} is invalid: {}", path.display(), e),
+                "Fix the JSON, or delete the file to start from the defaults",
+            ),
+        },
+        Err(e) => Check::fail(
+            "Config file",
+            format!("{} can't be read: {}", path.display(), e),
+            "Check the file's permissions",
+        ),
+    }
+}
+
+/// Checks every external provider, only failing for the one that's selected
+fn check_providers(selected: Provider) -> Vec<Check> {
+    Provider::ALL
+        .iter()
+        .filter_map(|provider| {
+            let binary = provider.binary()?;
+            let name = format!("Provider {}", provider.name());
+            let check = if provider.is_installed() {
+                Check::pass(name, format!("{} found", binary))
+            } else if *provider == selected {
+                Check::fail(
+                    name,
+                    format!("{} not found, but it is the selected provider", binary),
+                    format!("Install it: {}", provider.install_hint()),
+                )
+            } else {
+                Check::pass(name, format!("{} not installed (not selected)", binary))
+            };
+            Some(check)
+        })
+        .collect()
+}
+
+async fn check_local_service(config: &Config) -> Check {
+    let target = match &config.local_socket {
+        Some(path) if config.provider == Provider::Builtin => LocalTarget::Unix(path.clone()),
+        _ => LocalTarget::Tcp(strip_brackets(&config.local_host).to_string(), config.port),
+    };
+
+    let reachable = match &target {
+        LocalTarget::Tcp(host, port) | LocalTarget::Tls(host, port) => {
+            matches!(timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), *port))).await, Ok(Ok(_)))
+        }
+        #[cfg(unix)]
+        LocalTarget::Unix(path) => matches!(
+            timeout(CONNECT_TIMEOUT, tokio::net::UnixStream::connect(path)).await,
+            Ok(Ok(_))
+        ),
+        #[cfg(not(unix))]
+        LocalTarget::Unix(_) => false,
+    };
+
+    if reachable {
+        Check::pass("Local service", format!("{} is accepting connections", target))
+    } else {
+        Check::fail(
+            "Local service",
+            format!("nothing is listening on {}", target),
+            "Start your application, or point rshare at it with --port, --local-host or --local-socket",
+        )
+    }
+}
+
+async fn check_tunnel_server(config: &Config) -> Check {
+    let candidates = if config.servers.is_empty() {
+        vec![format!("{}:{}", config.server_host, config.server_port)]
+    } else {
+        config.servers.clone()
+    };
+
+    match crate::tunnel::client::select_server(&candidates).await {
+        Ok((server, latency)) => Check::pass(
+            "Tunnel server",
+            format!("{} reachable ({} ms)", server, latency.as_millis()),
+        ),
+        Err(_) => Check::fail(
+            "Tunnel server",
+            format!("could not connect to {}", candidates.join(", ")),
+            "Check the server is running and that --server-host/--public-port or --server-url are right",
+        ),
+    }
+}
+
+fn check_terminal() -> Check {
+    if !std::io::stdout().is_terminal() {
+        return Check::fail(
+            "Terminal",
+            "stdout is not a terminal",
+            "Run rshare from an interactive terminal to use the TUI",
+        );
+    }
+
+    match crossterm::terminal::size() {
+        Ok((width, height)) => {
+            let term = std::env::var("TERM").unwrap_or_else(|_| "unknown".to_string());
+            Check::pass("Terminal", format!("{}x{}, TERM={}", width, height, term))
+        }
+        Err(e) => Check::fail(
+            "Terminal",
+            format!("size could not be determined: {}", e),
+            "Try a different terminal emulator or set TERM",
+        ),
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper::header::{HOST, USER_AGENT};
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use crate::tunnel::client::{host_port, strip_brackets};
+use crate::tunnel::tls;
+
+/// Sends requests over a single HTTP/1.1 connection
+pub type Connection = SendRequest<Empty<Bytes>>;
+
+/// Opens a connection to the host in `uri`, over TLS checked against the
+/// public certificate authorities for `https` URLs
+pub async fn connect(uri: &Uri) -> Result<Connection> {
+    let host = strip_brackets(uri.host().ok_or_else(|| anyhow!("{} has no host", uri))?);
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let stream = TcpStream::connect((host, port))
+        .await
+        .with_context(|| format!("Failed to connect to {}", host_port(host, port)))?;
+    if !https {
+        return handshake(stream).await;
+    }
+
+    let server_name = ServerName::try_from(host.to_string())
+        .with_context(|| format!("{} is not a valid TLS server name", host))?;
+    let stream = tls::public_connector()
+        .connect(server_name, stream)
+        .await
+        .with_context(|| format!("TLS handshake with {} failed", host))?;
+    handshake(stream).await
+}
+
+/// Builds a GET request for `uri` to send on a connection from `connect`
+pub fn get(uri: &Uri) -> Result<Request<Empty<Bytes>>> {
+    let authority = uri
+        .authority()
+        .ok_or_else(|| anyhow!("{} has no host", uri))?;
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+
+    Ok(Request::get(path)
+        .header(HOST, authority.as_str())
+        .header(USER_AGENT, concat!("rshare/", env!("CARGO_PKG_VERSION")))
+        .body(Empty::new())?)
+}
+
+async fn handshake<S>(stream: S) -> Result<Connection>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(connection);
+    Ok(sender)
+}
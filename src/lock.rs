@@ -0,0 +1,124 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config;
+
+/// Marks this process as the running client instance for as long as it is
+/// held, so a second TUI doesn't share the config and client id with it
+pub struct InstanceLock {
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Takes the lock, failing if another live instance holds it unless `force`
+    /// is set. A lock left behind by a process that has exited is taken over.
+    pub fn acquire(force: bool) -> Result<Self> {
+        let config_path = config::get_config_path()?;
+        let config_dir = config_path.parent().unwrap();
+        fs::create_dir_all(config_dir)
+            .with_context(|| format!("Failed to create {}", config_dir.display()))?;
+        let path = config_dir.join("rshare.lock");
+
+        let mut file = match create_new(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|pid| pid.trim().parse::<u32>().ok());
+                if let Some(pid) = holder.filter(|pid| is_running(*pid)) {
+                    if !force {
+                        bail!(
+                            "Another instance of rshare is running (pid {}). Use --force to start anyway, or remove {} if it is stale",
+                            pid,
+                            path.display()
+                        );
+                    }
+                }
+                // Clear the stale or forced lock and claim it the same way, so
+                // an instance starting at the same moment can't also take it
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(e)
+                            .with_context(|| format!("Failed to replace {}", path.display()))
+                    }
+                    _ => {}
+                }
+                create_new(&path).with_context(|| {
+                    format!("Another instance of rshare took {} first", path.display())
+                })?
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()))
+            }
+        };
+
+        write!(file, "{}", std::process::id())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(Self { path })
+    }
+}
+
+/// Creates the lock file, failing if it already exists
+fn create_new(path: &Path) -> std::io::Result<fs::File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Leave a lock taken over with --force to the instance that now holds it
+        let ours = fs::read_to_string(&self.path)
+            .is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Whether a process with this id is still alive
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // 0 and out of range ids would signal a whole process group
+    let Some(pid) = libc::pid_t::try_from(pid).ok().filter(|pid| *pid > 0) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists, nothing is sent
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // A process owned by another user exists but can't be signalled
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether a process with this id is still alive
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{
+        CloseHandle, GetLastError, ERROR_ACCESS_DENIED, STILL_ACTIVE,
+    };
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    // SAFETY: the handle is checked before use and closed once queried
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            // A process we may not query exists all the same
+            return GetLastError() == ERROR_ACCESS_DENIED;
+        }
+        let mut exit_code = 0;
+        let alive =
+            GetExitCodeProcess(handle, &mut exit_code) != 0 && exit_code == STILL_ACTIVE as u32;
+        CloseHandle(handle);
+        alive
+    }
+}
+
+/// Without a cheap liveness check, treat any lock as held
+#[cfg(not(any(unix, windows)))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
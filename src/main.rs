@@ -1,29 +1,94 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
+use regex::RegexSet;
 use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
 
 mod app;
+mod audit;
+mod bench;
+mod capture;
+mod clipboard;
 mod config;
+mod diagnostics;
+mod http_client;
+mod lock;
+mod subdomain;
 mod tunnel;
 mod ui;
+mod update;
+
+/// How long to wait for input before redrawing with any new tunnel events
+const TICK_RATE: Duration = Duration::from_millis(250);
+
+/// Environment variable `rshare logs` reads the server's admin token from
+const ADMIN_TOKEN_ENV: &str = "RSHARE_ADMIN_TOKEN";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Port to expose
     #[arg(short, long, default_value_t = 8080)]
     port: u16,
 
+    /// Tunnel the first service found on a common dev port (3000, 5173, 8000, 8080, 4200)
+    #[arg(long)]
+    auto_port: bool,
+
+    /// Host the local service listens on, e.g. ::1 for an IPv6-only service (builtin provider only)
+    #[arg(long, value_name = "HOST")]
+    local_host: Option<String>,
+
+    /// Unix domain socket to expose instead of a TCP port (builtin provider only)
+    #[arg(long, value_name = "PATH")]
+    local_socket: Option<PathBuf>,
+
+    /// Host header to send to the local service instead of the public host (e.g. myapp.local)
+    #[arg(long, value_name = "HOST")]
+    forward_host_header: Option<String>,
+
+    /// Path the local app is mounted under, e.g. /myapp (GET / is forwarded as GET /myapp/)
+    #[arg(long, value_name = "PATH")]
+    base_path: Option<String>,
+
+    /// Header added to every response served through the tunnel, e.g. "Cache-Control: no-store" (repeatable)
+    #[arg(long = "response-header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    response_headers: Vec<(String, String)>,
+
     /// Domain to use (e.g., your-subdomain.dev.peril.lol)
     #[arg(short, long)]
     domain: Option<String>,
 
+    /// Tunnel provider to use
+    #[arg(long, value_enum)]
+    provider: Option<tunnel::Provider>,
+
+    /// Extra argument passed to the ngrok/cloudflared/localtunnel command (repeatable)
+    #[arg(long = "provider-arg", allow_hyphen_values = true)]
+    provider_args: Vec<String>,
+
+    /// Token identifying you to the tunnel server, used to claim reserved subdomains
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Host running the tunnel server
+    #[arg(long)]
+    server_host: Option<String>,
+
+    /// Tunnel server to connect to as `host:port` (repeatable, the fastest is used)
+    #[arg(long = "server-url")]
+    servers: Vec<String>,
+
     /// Public port to listen on for the tunnel server (only relevant when running in server mode)
     #[arg(short = 'P', long, default_value_t = 8000)]
     public_port: u16,
@@ -31,6 +96,128 @@ struct Args {
     /// Run in server mode (tunnel server) instead of client mode (tunnel client)
     #[arg(short, long)]
     server: bool,
+
+    /// Copy each tunneled connection's request and response to this address, e.g. 127.0.0.1:9000 (builtin provider only)
+    #[arg(long, value_name = "ADDR")]
+    tee: Option<String>,
+
+    /// Reach the tunnel server through this proxy, e.g. http://proxy:3128 or socks5://proxy:1080 (default: HTTP_PROXY, HTTPS_PROXY or ALL_PROXY)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Stop the tunnel on the first disconnect instead of reconnecting
+    #[arg(long)]
+    no_reconnect: bool,
+
+    /// Give up after this many failed reconnect attempts, 0 to keep trying forever (builtin provider only)
+    #[arg(long, value_name = "N")]
+    max_reconnect_attempts: Option<u32>,
+
+    /// Forward at most this many requests to the local service at once, queueing the rest (builtin provider only)
+    #[arg(long, value_name = "N")]
+    max_concurrent: Option<usize>,
+
+    /// Append tunnel lifecycle events to this file as JSON lines
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Keep the last requests' bodies in memory, redacted, to look at with [b] (builtin provider only)
+    #[arg(long)]
+    capture_bodies: bool,
+
+    /// Number of log lines to keep in the TUI (default 1000)
+    #[arg(long, value_name = "LINES")]
+    log_retention: Option<usize>,
+
+    /// Only record errors in the log pane (toggle with S)
+    #[arg(long)]
+    silent_logs: bool,
+
+    /// How log lines are stamped (cycle with T)
+    #[arg(long, value_enum, value_name = "STYLE")]
+    log_timestamps: Option<app::LogTimestamps>,
+
+    /// Leave the mouse to the terminal, so text can be selected and copied natively
+    #[arg(long)]
+    no_mouse: bool,
+
+    /// Where [c] copies the tunnel URL to (default: detect the best available)
+    #[arg(long, value_enum, value_name = "BACKEND")]
+    clipboard: Option<clipboard::ClipboardBackend>,
+
+    /// Check for a newer rshare release on GitHub at startup
+    #[arg(long, conflicts_with = "no_update_check")]
+    update_check: bool,
+
+    /// Skip the release check for this run even if the config turns it on
+    #[arg(long)]
+    no_update_check: bool,
+
+    /// Run without the TUI, printing the resolved settings, the tunnel URL and
+    /// log lines to stdout until interrupted (e.g. in CI)
+    #[arg(long)]
+    headless: bool,
+
+    /// Start even if another instance of rshare appears to be running
+    #[arg(long)]
+    force: bool,
+
+    /// Seconds to let in-flight requests finish when stopping the tunnel (default 5, 0 stops immediately)
+    #[arg(long, value_name = "SECS")]
+    drain_secs: Option<u64>,
+
+    /// Only print errors when running in server mode
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Fall back to a free port if the server port is already in use
+    #[arg(long)]
+    fallback_port: bool,
+
+    /// Show a live table of connected clients instead of printing log lines (server mode)
+    #[arg(long)]
+    dashboard: bool,
+
+    /// Reject anything but GET and HEAD requests when running in server mode
+    #[arg(long)]
+    read_only: bool,
+
+    /// Answer requests whose path matches this regex with 403 instead of forwarding them, e.g. '^/\.(env|git)' (server mode, repeatable)
+    #[arg(long, value_name = "REGEX")]
+    block_paths: Vec<String>,
+
+    /// Correct a missing or generic Content-Type from the path's file extension (server mode)
+    #[arg(long)]
+    fix_mime_types: bool,
+
+    /// Delay every forwarded response by this many milliseconds in server mode (for testing timeouts)
+    #[arg(long, value_name = "MS")]
+    inject_delay: Option<u64>,
+
+    /// Answer each request with a JSON description of what would be forwarded, instead of forwarding it (server mode)
+    #[arg(long)]
+    expose_headers: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the environment for common setup problems
+    Doctor,
+    /// Fire requests at a tunnel URL and report throughput and latency
+    Bench {
+        /// URL to request, e.g. the tunnel's public URL
+        #[arg(long)]
+        url: String,
+        /// Total number of requests to send
+        #[arg(long, default_value_t = 100)]
+        requests: usize,
+        /// Number of requests in flight at once
+        #[arg(long, default_value_t = 10)]
+        concurrency: usize,
+    },
+    /// Stream a remote tunnel server's logs, authorized by the server's
+    /// `admin_token` from RSHARE_ADMIN_TOKEN or the config file
+    Logs,
 }
 
 #[tokio::main]
@@ -38,23 +225,93 @@ async fn main() -> Result<()> {
     // Parse command line arguments
     let args = Args::parse();
 
+    if let Some(Command::Doctor) = args.command {
+        let mut config = config::Config::default();
+        if config::Config::exists() {
+            // A broken config is reported by the checks themselves
+            config = config::Config::load().unwrap_or_default();
+        }
+        apply_overrides(&args, &mut config);
+        return diagnostics::run(&config).await;
+    }
+
+    if let Some(Command::Bench {
+        url,
+        requests,
+        concurrency,
+    }) = &args.command
+    {
+        return bench::run(url, *requests, *concurrency).await;
+    }
+
+    if let Some(Command::Logs) = &args.command {
+        let mut config = config::Config::load()?;
+        apply_overrides(&args, &mut config);
+        // Kept off the command line, where other users could read it from `ps`
+        let admin_token = std::env::var(ADMIN_TOKEN_ENV)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .or_else(|| config.server.admin_token.clone())
+            .with_context(|| {
+                format!(
+                    "Set {} or server.admin_token in the config to the server's admin token",
+                    ADMIN_TOKEN_ENV
+                )
+            })?;
+        let server_addr = format!("{}:{}", config.server_host, config.server_port);
+        return tunnel::client::tail_server_logs(
+            &server_addr,
+            &admin_token,
+            config.tls.as_ref(),
+            config.proxy.as_deref(),
+        )
+        .await;
+    }
+
     // Check if running in server mode
     if args.server {
-        println!("Starting tunnel server on port {}", args.public_port);
-        tunnel::server::run(args.public_port).await?;
+        if !args.quiet {
+            println!("Starting tunnel server on port {}", args.public_port);
+        }
+        let options = server_options(&args)?;
+        tunnel::server::run(args.public_port, options, move || server_options(&args)).await?;
         return Ok(());
     }
 
+    // Load config, letting command line arguments override its values
+    let first_run = !config::Config::exists();
+    let mut config = config::Config::load().unwrap_or_else(|e| {
+        eprintln!("Error loading config: {}", e);
+        config::Config::default()
+    });
+    apply_overrides(&args, &mut config);
+
+    // Held until exit so a second TUI doesn't share our config and client id
+    let _instance_lock = lock::InstanceLock::acquire(args.force)?;
+
+    if args.headless {
+        return run_headless(app::App::new(config)).await;
+    }
+
     // Client mode - Show TUI
     // Setup terminal
+    install_panic_hook();
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    execute!(stdout, EnterAlternateScreen)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
     // Create app state
-    let mut app = app::App::new(args.port, args.domain, args.public_port);
+    let mut app = app::App::new(config);
+    if app.mouse_capture {
+        execute!(io::stdout(), EnableMouseCapture)?;
+    }
+
+    // Help new users choose a provider unless they already picked one
+    if first_run && args.provider.is_none() {
+        app.enter_pick_provider_mode();
+    }
 
     // Run app
     let res = run_app(&mut terminal, &mut app).await;
@@ -75,59 +332,355 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Restores the terminal before a panic is reported, so a crash doesn't leave
+/// it in raw mode on the alternate screen
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture);
+        default_hook(info);
+    }));
+}
+
+/// Builds the server's options from the config file and command line, run
+/// at startup and again whenever the server is asked to reload its config
+fn server_options(args: &Args) -> Result<tunnel::server::ServerOptions> {
+    let server_config = config::Config::load()?.server;
+    if server_config.client_queue_size == Some(0) {
+        anyhow::bail!("server.client_queue_size must be at least 1");
+    }
+    if server_config.max_header_count == Some(0) {
+        anyhow::bail!("server.max_header_count must be at least 1");
+    }
+    if server_config.max_header_bytes == Some(0) {
+        anyhow::bail!("server.max_header_bytes must be at least 1");
+    }
+    let keepalive_interval = tunnel::client::KEEPALIVE_INTERVAL.as_secs();
+    if server_config.heartbeat_timeout.is_some_and(|secs| secs <= keepalive_interval) {
+        anyhow::bail!(
+            "server.heartbeat_timeout must be longer than the {} second interval clients send keep-alives at",
+            keepalive_interval
+        );
+    }
+    let landing_page = server_config.landing_page_html()?;
+    let reservations = server_config.load_reservations()?;
+    let block_paths = server_config.block_paths.iter().chain(&args.block_paths);
+    let block_paths = if server_config.block_paths.is_empty() && args.block_paths.is_empty() {
+        None
+    } else {
+        Some(RegexSet::new(block_paths).context("Invalid blocked path pattern")?)
+    };
+    Ok(tunnel::server::ServerOptions {
+        quiet: args.quiet,
+        fallback_port: args.fallback_port,
+        landing_page,
+        landing_redirect: server_config.landing_redirect,
+        maintenance: server_config.maintenance,
+        max_clients: server_config.max_clients,
+        retry_after: server_config.retry_after,
+        reservations,
+        require_reservation: server_config.require_reservation,
+        read_only: args.read_only,
+        block_paths,
+        probe_paths: server_config.probe_paths,
+        cors: server_config.cors,
+        expose_headers: args.expose_headers,
+        inject_delay: args.inject_delay.map(Duration::from_millis),
+        traffic_cap: server_config.traffic_cap,
+        spool_threshold: server_config.spool_threshold,
+        admin_token: server_config.admin_token,
+        connect_token: server_config.connect_token,
+        request_headers: server_config.request_headers,
+        tls: server_config.tls,
+        retry_on_reconnect: server_config.retry_on_reconnect,
+        response_cache: server_config.response_cache,
+        fix_mime_types: server_config.fix_mime_types || args.fix_mime_types,
+        dashboard: args.dashboard,
+        client_queue_size: server_config.client_queue_size,
+        heartbeat_timeout: server_config.heartbeat_timeout.map(Duration::from_secs),
+        max_header_count: server_config.max_header_count,
+        max_header_bytes: server_config.max_header_bytes,
+    })
+}
+
+/// Parses a `Name: value` header given on the command line
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got {:?}", header))?;
+    if name.trim().is_empty() {
+        return Err("header name is empty".to_string());
+    }
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// Applies any values given on the command line on top of the config file
+fn apply_overrides(args: &Args, config: &mut config::Config) {
+    if args.port != 8080 {
+        config.port = args.port;
+    }
+    if args.public_port != 8000 {
+        config.server_port = args.public_port;
+    }
+    if args.auto_port {
+        config.auto_port = true;
+    }
+    if let Some(local_host) = &args.local_host {
+        config.local_host = local_host.clone();
+    }
+    if let Some(local_socket) = &args.local_socket {
+        config.local_socket = Some(local_socket.clone());
+    }
+    if let Some(host) = &args.forward_host_header {
+        config.forward_host_header = Some(host.clone());
+    }
+    if let Some(base_path) = &args.base_path {
+        config.base_path = Some(base_path.clone());
+    }
+    for (name, value) in &args.response_headers {
+        config.response_headers.insert(name.clone(), value.clone());
+    }
+    if let Some(domain) = &args.domain {
+        config.domain = Some(domain.clone());
+    }
+    if let Some(server_host) = &args.server_host {
+        config.server_host = server_host.clone();
+    }
+    if let Some(provider) = args.provider {
+        config.provider = provider;
+    }
+    if !args.provider_args.is_empty() {
+        config.provider_args = args.provider_args.clone();
+    }
+    if !args.servers.is_empty() {
+        config.servers = args.servers.clone();
+    }
+    if let Some(tee) = &args.tee {
+        config.tee = Some(tee.clone());
+    }
+    if let Some(proxy) = &args.proxy {
+        config.proxy = Some(proxy.clone());
+    }
+    if let Some(max) = args.max_concurrent {
+        config.max_concurrent = Some(max);
+    }
+    if let Some(audit_log) = &args.audit_log {
+        config.audit_log = Some(audit_log.clone());
+    }
+    if args.capture_bodies && config.capture.is_none() {
+        config.capture = Some(config::CaptureConfig::default());
+    }
+    if args.no_reconnect {
+        config.no_reconnect = true;
+    }
+    if let Some(max) = args.max_reconnect_attempts {
+        config.max_reconnect_attempts = max;
+    }
+    if let Some(lines) = args.log_retention {
+        config.log_retention = lines;
+    }
+    if args.silent_logs {
+        config.silent_logs = true;
+    }
+    if let Some(style) = args.log_timestamps {
+        config.log_timestamps = style;
+    }
+    if args.no_mouse {
+        config.no_mouse = true;
+    }
+    if let Some(backend) = args.clipboard {
+        config.clipboard = backend;
+    }
+    if args.update_check {
+        config.update_check = true;
+    }
+    if args.no_update_check {
+        config.update_check = false;
+    }
+    if let Some(secs) = args.drain_secs {
+        config.drain_secs = secs;
+    }
+    if let Some(token) = &args.token {
+        config.token = Some(token.clone());
+    }
+}
+
+/// Runs the tunnel without the TUI, printing a banner with the resolved
+/// settings, then the URL and each log line as they arrive, until Ctrl-C
+async fn run_headless(mut app: app::App) -> Result<()> {
+    print_banner(&app);
+    let started = app.start_tunnel().await;
+    print_new_logs(&mut app);
+    started?;
+
+    let mut url_shown = None;
+    loop {
+        if !app.tunnel_active {
+            anyhow::bail!(app
+                .connection_error
+                .clone()
+                .unwrap_or_else(|| "The tunnel stopped".to_string()));
+        }
+        app.process_tunnel_events();
+        app.poll_update_check();
+        if app.tunnel_url != url_shown {
+            if let Some(url) = &app.tunnel_url {
+                println!("URL: {}", url);
+            }
+            url_shown = app.tunnel_url.clone();
+        }
+        print_new_logs(&mut app);
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = tokio::time::sleep(TICK_RATE) => {}
+        }
+    }
+
+    if app.tunnel_active {
+        app.stop_tunnel().await?;
+    }
+    print_new_logs(&mut app);
+    Ok(())
+}
+
+/// Summarizes what headless mode is about to expose, so its output explains itself
+fn print_banner(app: &app::App) {
+    println!("rshare {}", update::CURRENT_VERSION);
+    if app.auto_port && app.local_socket.is_none() {
+        println!("  Local:    first service found on a common dev port");
+    } else {
+        println!("  Local:    {}", app.local_target());
+    }
+    println!("  Provider: {}", app.provider.name());
+    if app.provider == tunnel::Provider::Builtin {
+        println!("  Server:   {}:{}", app.server_host, app.server_port);
+    }
+    println!(
+        "  Domain:   {}",
+        app.domain.as_deref().unwrap_or("(assigned by the provider)")
+    );
+}
+
+/// Prints the log lines added since the last call; headless mode has no log
+/// pane, so they aren't kept once printed
+fn print_new_logs(app: &mut app::App) {
+    for entry in app.logs.drain(..) {
+        println!("[{}] {}", entry.at.format("%H:%M:%S"), entry.message);
+    }
+}
+
 async fn run_app<B: ratatui::backend::Backend>(
     terminal: &mut Terminal<B>,
     app: &mut app::App,
 ) -> Result<()> {
     loop {
+        app.process_tunnel_events();
+        app.poll_update_check();
         terminal.draw(|f| ui::draw(f, app))?;
 
-        if let Event::Key(key) = event::read()? {
-            match app.mode {
-                app::AppMode::Normal => {
-                    match key.code {
-                        KeyCode::Char('q') => {
-                            if app.tunnel_active {
-                                app.stop_tunnel().await?;
-                            }
-                            return Ok(());
-                        }
-                        KeyCode::Char('s') => {
-                            if !app.tunnel_active {
-                                app.start_tunnel().await?;
-                            } else {
-                                app.stop_tunnel().await?;
-                            }
-                        }
-                        KeyCode::Char('p') => {
-                            app.enter_config_port_mode();
+        if !event::poll(TICK_RATE)? {
+            continue;
+        }
+
+        let key = match event::read()? {
+            // Redraw at the new size straight away rather than on the next key
+            Event::Resize(_, _) => {
+                terminal.autoresize()?;
+                continue;
+            }
+            Event::Key(key) => key,
+            _ => continue,
+        };
+
+        match app.mode {
+            app::AppMode::Normal => {
+                match key.code {
+                    KeyCode::Char('q') => {
+                        if app.tunnel_active {
+                            app.stop_tunnel().await?;
                         }
-                        KeyCode::Char('P') => {
-                            app.enter_config_server_port_mode();
+                        return Ok(());
+                    }
+                    KeyCode::Char('s') => {
+                        if !app.tunnel_active {
+                            app.start_tunnel().await?;
+                        } else {
+                            app.stop_tunnel().await?;
                         }
-                        KeyCode::Up => app.scroll_logs_up(),
-                        KeyCode::Down => app.scroll_logs_down(),
-                        _ => {}
                     }
+                    KeyCode::Char('p') => {
+                        app.enter_config_port_mode();
+                    }
+                    KeyCode::Char('P') => {
+                        app.enter_config_server_port_mode();
+                    }
+                    KeyCode::Char('o') => app.enter_pick_provider_mode(),
+                    KeyCode::Char('t') => app.cycle_provider()?,
+                    KeyCode::Char('n') => app.regenerate_url().await?,
+                    KeyCode::Char('R') => app.restart_tunnel().await?,
+                    KeyCode::Char('C') => app.clear_logs(),
+                    KeyCode::Char('S') => app.toggle_silent_logs(),
+                    KeyCode::Char('T') => app.cycle_log_timestamps(),
+                    KeyCode::Char('x') => app.log_share_command(),
+                    KeyCode::Char('e') => app.export_logs(),
+                    KeyCode::Char('c') => app.copy_url(),
+                    KeyCode::Char('b') => app.enter_show_capture_mode(),
+                    // Let the terminal select text while the popup is open
+                    KeyCode::Char('u') if app.enter_show_url_mode() => {
+                        execute!(io::stdout(), DisableMouseCapture)?;
+                    }
+                    KeyCode::Char(' ') => app.toggle_follow_logs(),
+                    KeyCode::Up => app.scroll_logs_up(),
+                    KeyCode::Down => app.scroll_logs_down(),
+                    _ => {}
                 }
-                app::AppMode::ConfigPort | app::AppMode::ConfigServerPort => {
-                    match key.code {
-                        KeyCode::Esc => {
-                            app.exit_config_mode();
-                        }
-                        KeyCode::Enter => {
-                            app.apply_config()?;
-                        }
-                        KeyCode::Char(c) => {
-                            app.handle_key_input(c);
-                        }
-                        KeyCode::Backspace => {
-                            app.handle_key_input('\u{8}');
-                        }
-                        _ => {}
+            }
+            app::AppMode::PickProvider => {
+                match key.code {
+                    KeyCode::Up => app.select_previous_provider(),
+                    KeyCode::Down => app.select_next_provider(),
+                    KeyCode::Enter => app.confirm_provider()?,
+                    KeyCode::Esc => app.exit_config_mode(),
+                    _ => {}
+                }
+            }
+            app::AppMode::ShowCapture => {
+                match key.code {
+                    KeyCode::Left => app.select_older_capture(),
+                    KeyCode::Right => app.select_newer_capture(),
+                    KeyCode::Up => app.capture_scroll = app.capture_scroll.saturating_sub(1),
+                    KeyCode::Down => app.capture_scroll = app.capture_scroll.saturating_add(1),
+                    KeyCode::Esc | KeyCode::Char('b') | KeyCode::Char('q') => app.exit_config_mode(),
+                    _ => {}
+                }
+            }
+            app::AppMode::ShowUrl => {
+                if matches!(key.code, KeyCode::Esc | KeyCode::Enter | KeyCode::Char('u') | KeyCode::Char('q')) {
+                    app.exit_config_mode();
+                    if app.mouse_capture {
+                        execute!(io::stdout(), EnableMouseCapture)?;
                     }
                 }
             }
+            app::AppMode::ConfigPort | app::AppMode::ConfigServerPort => {
+                match key.code {
+                    KeyCode::Esc => {
+                        app.exit_config_mode();
+                    }
+                    KeyCode::Enter => {
+                        app.apply_config()?;
+                    }
+                    KeyCode::Char(c) => {
+                        app.handle_key_input(c);
+                    }
+                    KeyCode::Backspace => {
+                        app.handle_key_input('\u{8}');
+                    }
+                    _ => {}
+                }
+            }
         }
     }
-}
\ No newline at end of file
+}
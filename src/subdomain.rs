@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// How the subdomain is generated when no domain is configured
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SubdomainStyle {
+    /// A full UUID, e.g. `3f2b8c1e-...`
+    #[default]
+    Uuid,
+    /// Six base32 characters, e.g. `a1b2c3`
+    Short,
+    /// Two words and a number, e.g. `brave-otter-42`
+    Words,
+}
+
+/// Lowercase RFC 4648 base32 alphabet, safe for DNS labels
+const BASE32: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "eager", "fancy",
+    "gentle", "golden", "happy", "jolly", "keen", "lively", "lucky", "mellow", "misty", "noble",
+    "polite", "quick", "quiet", "rapid", "shiny", "silent", "sunny", "swift", "tidy", "vivid",
+    "witty", "zesty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "beacon", "breeze", "canyon", "comet", "falcon", "forest", "harbor", "heron",
+    "island", "lagoon", "lantern", "maple", "meadow", "orbit", "otter", "panda", "pebble",
+    "pine", "planet", "puffin", "raven", "river", "rocket", "sparrow", "summit", "tiger",
+    "tulip", "valley", "walrus", "willow", "zephyr",
+];
+
+/// Generates a subdomain label in the given style
+pub fn generate(style: SubdomainStyle) -> String {
+    let uuid = Uuid::new_v4();
+    // The random bits of a v4 UUID double as our source of randomness
+    let bytes = uuid.as_bytes();
+
+    match style {
+        SubdomainStyle::Uuid => uuid.to_string(),
+        SubdomainStyle::Short => {
+            // Skip the version nibble in the first half
+            let bits = u64::from_be_bytes(bytes[8..].try_into().unwrap());
+            (0..6)
+                .map(|i| BASE32[((bits >> (i * 5)) & 0x1f) as usize] as char)
+                .collect()
+        }
+        SubdomainStyle::Words => format!(
+            "{}-{}-{}",
+            ADJECTIVES[bytes[0] as usize % ADJECTIVES.len()],
+            NOUNS[bytes[1] as usize % NOUNS.len()],
+            bytes[2] % 100
+        ),
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::tunnel::http::ParsedResponse;
+
+/// A small least-recently-used cache of local service responses, each served
+/// for a fixed time after it was stored
+pub struct ResponseCache {
+    max_entries: usize,
+    ttl: Duration,
+    entries: HashMap<String, (Instant, ParsedResponse)>,
+    /// Keys from least to most recently used
+    order: VecDeque<String>,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            max_entries: max_entries.max(1),
+            ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns a copy of the response stored under `key` if it hasn't expired
+    pub fn get(&mut self, key: &str) -> Option<ParsedResponse> {
+        let (stored_at, response) = self.entries.get(key)?;
+        if stored_at.elapsed() >= self.ttl {
+            self.remove(key);
+            return None;
+        }
+        let response = response.clone();
+        self.touch(key);
+        Some(response)
+    }
+
+    /// Stores a response, evicting the least recently used ones when full
+    pub fn insert(&mut self, key: String, response: ParsedResponse) {
+        if self.entries.contains_key(&key) {
+            self.touch(&key);
+        } else {
+            self.order.push_back(key.clone());
+        }
+        self.entries.insert(key, (Instant::now(), response));
+
+        while self.entries.len() > self.max_entries {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(position) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(position).unwrap();
+            self.order.push_back(key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+        self.order.retain(|k| k != key);
+    }
+}
+
+/// Whether a response may be shared with other viewers: a plain success that
+/// doesn't set cookies or ask not to be stored. Entries are keyed on the
+/// method and URI alone, so responses that depend on other request headers
+/// (a `Vary` on anything but `Origin`, or a negotiated `Content-Encoding`)
+/// are never stored.
+pub fn is_cacheable(response: &ParsedResponse) -> bool {
+    response.status == 200
+        && response.headers.iter().all(|(name, value)| {
+            if name.eq_ignore_ascii_case("set-cookie")
+                || name.eq_ignore_ascii_case("content-encoding")
+            {
+                return false;
+            }
+            if name.eq_ignore_ascii_case("vary") {
+                return value
+                    .split(',')
+                    .map(str::trim)
+                    .all(|header| header.is_empty() || header.eq_ignore_ascii_case("origin"));
+            }
+            if name.eq_ignore_ascii_case("cache-control") {
+                let value = value.to_lowercase();
+                return !["no-store", "no-cache", "private"]
+                    .iter()
+                    .any(|directive| value.contains(directive));
+            }
+            true
+        })
+}
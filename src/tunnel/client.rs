@@ -1,215 +1,1498 @@
 use anyhow::{Context, Result};
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{future::join_all, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::process::Stdio;
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::process::Command;
-use tokio::sync::mpsc;
-use tokio::time::sleep;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_tungstenite::{
+    client_async, connect_async,
+    tungstenite::{self, client::IntoClientRequest, protocol::Message},
+    MaybeTlsStream, WebSocketStream,
+};
 
-use crate::tunnel::TunnelResult;
+use crate::config::TlsConfig;
+use crate::tunnel::proxy::Proxy;
+use crate::tunnel::sequence::Reorder;
+use crate::tunnel::{http, tls, TunnelError, TunnelEvent, TunnelResult};
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Path the tunnel WebSocket is opened on; the server closes connections to any other
+pub const REGISTER_PATH: &str = "/register";
+
+/// Path a lazily connected client opens its data channel on when the server asks
+pub const DATA_CHANNEL_PATH: &str = "/data";
+
+/// Path of the token-protected WebSocket streaming the server's log lines
+pub const ADMIN_LOGS_PATH: &str = "/admin/logs";
+
+/// Header carrying a request's trace id, on the request to the local service
+/// and on the response to the caller
+pub const TRACE_HEADER: &str = "x-rshare-trace";
+
+/// How long to wait before reconnecting when the server gives no hint
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the client tells the server it is still there, for servers that
+/// drop clients without a heartbeat
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long to wait for a candidate server to accept a connection
+const SERVER_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Chunks queued for a tee before further copies are dropped, so a slow tee
+/// never holds up forwarding
+const TEE_BUFFER: usize = 64;
+
+/// How long to wait for the local service to answer a TLS handshake
+const SCHEME_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where forwarded requests are delivered on this machine
+#[derive(Clone, Debug, PartialEq)]
+pub enum LocalTarget {
+    /// A TCP port on a local host name or address
+    Tcp(String, u16),
+    /// A TCP port on a local host name or address, serving HTTPS
+    Tls(String, u16),
+    /// A Unix domain socket, e.g. Docker or PHP-FPM
+    Unix(PathBuf),
+}
+
+impl fmt::Display for LocalTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LocalTarget::Tcp(host, port) => write!(f, "{}", host_port(host, *port)),
+            LocalTarget::Tls(host, port) => write!(f, "https://{}", host_port(host, *port)),
+            LocalTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+type LocalReader = Box<dyn AsyncRead + Unpin + Send>;
+type LocalWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+impl LocalTarget {
+    /// The addresses the target's host currently resolves to, none for a Unix socket
+    async fn resolve(&self) -> std::io::Result<Vec<SocketAddr>> {
+        match self {
+            LocalTarget::Tcp(host, port) | LocalTarget::Tls(host, port) => {
+                Ok(lookup_host((host.as_str(), *port)).await?.collect())
+            }
+            LocalTarget::Unix(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Opens a connection to the target at one of `addrs`, as returned by
+    /// `resolve`, split into its read and write halves
+    async fn connect(&self, addrs: &[SocketAddr]) -> std::io::Result<(LocalReader, LocalWriter)> {
+        match self {
+            LocalTarget::Tcp(_, _) => {
+                let stream = TcpStream::connect(addrs).await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            LocalTarget::Tls(_, _) => {
+                let stream = TcpStream::connect(addrs).await?;
+                let stream = tls::insecure_connector()
+                    .connect(local_server_name(), stream)
+                    .await?;
+                let (reader, writer) = tokio::io::split(stream);
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            #[cfg(unix)]
+            LocalTarget::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            #[cfg(not(unix))]
+            LocalTarget::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+/// Formats `host:port`, bracketing IPv6 addresses
+pub fn host_port(host: &str, port: u16) -> String {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Accepts a local host written as `[::1]` as well as `::1`, since addresses
+/// are only bracketed next to a port
+pub fn strip_brackets(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Removes an `http://` or `https://` prefix and trailing slash pasted along
+/// with a domain, leaving e.g. `app.example.com` or `app.example.com/api`
+pub fn strip_scheme(domain: &str) -> &str {
+    let lower = domain.to_ascii_lowercase();
+    let domain = ["https://", "http://"]
+        .iter()
+        .find(|scheme| lower.starts_with(*scheme))
+        .map_or(domain, |scheme| &domain[scheme.len()..]);
+    domain.trim_end_matches('/')
+}
+
+/// The only addresses the client is allowed to dial on behalf of the server.
+///
+/// The server never gets to choose where forwarded data ends up; every local
+/// connection is checked against the addresses the configured target resolved
+/// to when the session started, so a host name that later resolves elsewhere
+/// can't redirect traffic.
+#[derive(Clone, Debug)]
+struct EgressAllowlist {
+    target: LocalTarget,
+    /// `None` until the target has resolved
+    addrs: Option<Vec<SocketAddr>>,
+}
+
+impl EgressAllowlist {
+    /// Allows whatever the target resolves to right now. If it doesn't
+    /// resolve, e.g. because DNS is down, the next connection tries again.
+    async fn resolve(target: LocalTarget) -> Self {
+        let addrs = target.resolve().await.ok();
+        Self { target, addrs }
+    }
+
+    /// Resolves the target if that failed when the tunnel connected
+    async fn ensure_resolved(&mut self) -> Result<()> {
+        if self.addrs.is_none() {
+            let addrs = self
+                .target
+                .resolve()
+                .await
+                .with_context(|| format!("Failed to resolve {}", self.target))?;
+            self.addrs = Some(addrs);
+        }
+        Ok(())
+    }
+
+    /// Returns an error if any of `addrs`, about to be dialled, isn't allowed
+    fn check(&self, addrs: &[SocketAddr]) -> Result<()> {
+        let allowed = self.addrs.as_deref().unwrap_or_default();
+        if let Some(addr) = addrs.iter().find(|addr| !allowed.contains(addr)) {
+            return Err(anyhow::anyhow!(
+                "Refusing to connect to {}, which is not an address {} had when the tunnel connected",
+                addr,
+                self.target
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Name sent when dialling a local HTTPS service; its certificate isn't
+/// checked, so this only matters to servers that pick one by name
+fn local_server_name() -> ServerName<'static> {
+    ServerName::try_from("localhost").expect("localhost is a valid server name")
+}
+
+/// Works out whether the local service on `host` and `port` speaks HTTPS by
+/// attempting a TLS handshake, which plain HTTP servers reject. Returns `None`
+/// if nothing accepted the connection.
+pub async fn detect_https(host: &str, port: u16) -> Option<bool> {
+    let stream = timeout(SCHEME_PROBE_TIMEOUT, TcpStream::connect((host, port)))
+        .await
+        .ok()?
+        .ok()?;
+    let connector = tls::insecure_connector();
+    let handshake = timeout(SCHEME_PROBE_TIMEOUT, connector.connect(local_server_name(), stream)).await;
+    Some(matches!(handshake, Ok(Ok(_))))
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TunnelMessage {
     Register {
+        /// Subdomain label used when no domain is requested
         client_id: String,
+        /// Identifies this client instance across reconnects. Clients that
+        /// don't send one are identified by `client_id`.
+        #[serde(default)]
+        instance_id: Option<String>,
         domain: Option<String>,
+        #[serde(default)]
+        token: Option<String>,
+        /// Host header the local service expects in place of the public host
+        #[serde(default)]
+        host_header: Option<String>,
+        /// Path the local app is mounted under, prepended to forwarded paths
+        #[serde(default)]
+        base_path: Option<String>,
+        /// Headers added to every response served through the tunnel
+        #[serde(default)]
+        response_headers: HashMap<String, String>,
+        /// Whether the client understands binary `Data` frames
+        #[serde(default)]
+        binary_data: bool,
+        /// Whether the client numbers its `Data` frames and checks the
+        /// numbers on the frames it receives
+        #[serde(default)]
+        sequenced: bool,
+        /// Whether the client would rather carry `Data` on a separate
+        /// channel it opens only when the server has a request for it
+        #[serde(default)]
+        lazy_connect: bool,
     },
     Registered {
         url: String,
+        /// Whether `Data` is sent as binary frames for the rest of the session
+        #[serde(default)]
+        binary_data: bool,
+        /// Whether `Data` frames are numbered for the rest of the session
+        #[serde(default)]
+        sequenced: bool,
+        /// Token the client attaches its data channel with, set when the
+        /// server agreed to connect it lazily
+        #[serde(default)]
+        data_token: Option<String>,
     },
+    /// The server won't accept the registration right now
+    Rejected {
+        reason: String,
+        /// Seconds to wait before trying again
+        retry_after: Option<u64>,
+    },
+    /// Bytes for one public connection, identified by `connection_id`
     Data {
+        connection_id: u64,
+        /// Position of this frame among the connection's frames in the same
+        /// direction, counting from 0
+        #[serde(default)]
+        seq: u64,
         data: Vec<u8>,
     },
+    /// The sender has nothing more to send for this connection
+    Close {
+        connection_id: u64,
+    },
     KeepAlive,
+    /// The server has a request for a lazily connected client, which should
+    /// open its data channel
+    OpenDataChannel,
+    /// First message on a data channel, naming the session it belongs to
+    AttachDataChannel {
+        instance_id: String,
+        token: String,
+    },
+    /// Bytes this session may still forward before the server disconnects it
+    Quota {
+        remaining: u64,
+    },
+    /// The server has ended the tunnel and won't take it back this session
+    Deregister {
+        reason: String,
+    },
+    /// The other side couldn't handle a message we sent
+    Error {
+        message: String,
+    },
+}
+
+/// First byte of a binary `Data` frame. JSON frames always start with `{`.
+const DATA_FRAME_TAG: u8 = 0x01;
+
+/// First byte of a binary `Data` frame that carries a sequence number
+const SEQUENCED_DATA_FRAME_TAG: u8 = 0x02;
+
+/// Tag, connection id and payload length
+const DATA_FRAME_HEADER_LEN: usize = 1 + 8 + 4;
+
+/// Tag, connection id, sequence number and payload length
+const SEQUENCED_DATA_FRAME_HEADER_LEN: usize = 1 + 8 + 8 + 4;
+
+/// How `Data` frames are written for the rest of a session, as agreed at
+/// registration
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Framing {
+    /// Length-prefixed binary frames instead of JSON
+    pub binary: bool,
+    /// Frames carry sequence numbers the receiver checks and reorders by
+    pub sequenced: bool,
+}
+
+impl TunnelMessage {
+    /// Encodes the message for the wire. `Data` is sent as a tagged,
+    /// length-prefixed binary frame when binary framing was negotiated, which
+    /// avoids JSON-encoding every payload byte; everything else is JSON.
+    pub fn encode(&self, framing: Framing) -> Result<Vec<u8>> {
+        match self {
+            TunnelMessage::Data {
+                connection_id,
+                seq,
+                data,
+            } if framing.binary => {
+                let len = u32::try_from(data.len())?;
+                let mut frame = Vec::with_capacity(SEQUENCED_DATA_FRAME_HEADER_LEN + data.len());
+                if framing.sequenced {
+                    frame.push(SEQUENCED_DATA_FRAME_TAG);
+                    frame.extend_from_slice(&connection_id.to_be_bytes());
+                    frame.extend_from_slice(&seq.to_be_bytes());
+                } else {
+                    frame.push(DATA_FRAME_TAG);
+                    frame.extend_from_slice(&connection_id.to_be_bytes());
+                }
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.extend_from_slice(data);
+                Ok(frame)
+            }
+            _ => Ok(serde_json::to_vec(self)?),
+        }
+    }
+
+    /// Decodes a frame in any format
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let header_len = match frame.first() {
+            Some(&DATA_FRAME_TAG) => DATA_FRAME_HEADER_LEN,
+            Some(&SEQUENCED_DATA_FRAME_TAG) => SEQUENCED_DATA_FRAME_HEADER_LEN,
+            _ => return Ok(serde_json::from_slice(frame)?),
+        };
+
+        if frame.len() < header_len {
+            anyhow::bail!("truncated data frame header");
+        }
+        let connection_id = u64::from_be_bytes(frame[1..9].try_into()?);
+        let seq = if header_len == SEQUENCED_DATA_FRAME_HEADER_LEN {
+            u64::from_be_bytes(frame[9..17].try_into()?)
+        } else {
+            0
+        };
+        let len = u32::from_be_bytes(frame[header_len - 4..header_len].try_into()?) as usize;
+        let data = &frame[header_len..];
+        if data.len() != len {
+            anyhow::bail!("data frame declares {} bytes but carries {}", len, data.len());
+        }
+
+        Ok(TunnelMessage::Data {
+            connection_id,
+            seq,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// Longest prefix of a malformed frame included in log messages
+const FRAME_PREVIEW_LEN: usize = 200;
+
+/// A printable, truncated rendering of a frame for logging
+pub fn preview_frame(data: &[u8]) -> String {
+    let preview = String::from_utf8_lossy(&data[..data.len().min(FRAME_PREVIEW_LEN)]);
+    if data.len() > FRAME_PREVIEW_LEN {
+        format!("{:?}... ({} bytes)", preview, data.len())
+    } else {
+        format!("{:?}", preview)
+    }
+}
+
+/// Probes each candidate server and returns the one that accepted a TCP
+/// connection fastest, along with how long that took
+pub async fn select_server(candidates: &[String]) -> Result<(String, Duration)> {
+    let probes = candidates.iter().map(|candidate| async move {
+        let start = Instant::now();
+        match timeout(SERVER_PROBE_TIMEOUT, TcpStream::connect(candidate.as_str())).await {
+            Ok(Ok(_)) => Some((candidate.clone(), start.elapsed())),
+            _ => None,
+        }
+    });
+
+    join_all(probes)
+        .await
+        .into_iter()
+        .flatten()
+        .min_by_key(|(_, latency)| *latency)
+        .ok_or_else(|| TunnelError::NoServerReachable.into())
+}
+
+/// A request being forwarded, tracked until its connection closes
+struct RequestStats {
+    started: Instant,
+    request_bytes: usize,
+    response_bytes: usize,
+    /// Id the server tagged the request with
+    trace: Option<String>,
+    /// Method and path of the first request on the connection
+    request_line: Option<String>,
+    /// Status of the first response on the connection
+    status: Option<u16>,
+    /// Start of the raw request and response, when bodies are being captured
+    request: Vec<u8>,
+    response: Vec<u8>,
+}
+
+/// Appends as much of `data` to `kept` as fits under `limit`
+fn keep_prefix(kept: &mut Vec<u8>, data: &[u8], limit: usize) {
+    let room = limit.saturating_sub(kept.len());
+    kept.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+/// Prefixes a log line with the request's trace id, when it has one
+fn traced(trace: Option<&str>, message: String) -> String {
+    match trace {
+        Some(trace) => format!("[{}] {}", trace, message),
+        None => message,
+    }
+}
+
+/// What the client registers with, kept so it can re-register after a reconnect
+#[derive(Clone, Debug)]
+pub struct Registration {
+    /// Subdomain label used when no domain is set
+    pub client_id: String,
+    /// Identifies this run to the server, whatever the label looks like
+    pub instance_id: String,
+    pub domain: Option<String>,
+    pub token: Option<String>,
+    pub host_header: Option<String>,
+    pub base_path: Option<String>,
+    pub response_headers: HashMap<String, String>,
+    /// Mutual TLS to the server, if it requires it
+    pub tls: Option<TlsConfig>,
+    /// Proxy URL to reach the server through, overriding the proxy environment variables
+    pub proxy: Option<String>,
+    /// Only open a data channel when a request arrives, instead of carrying
+    /// `Data` on the registration socket
+    pub lazy_connect: bool,
+}
+
+/// How requests arriving through the tunnel are delivered locally
+#[derive(Clone, Debug)]
+pub struct Forwarding {
+    pub local_target: LocalTarget,
+    /// Re-register automatically when the connection to the server drops
+    pub reconnect: bool,
+    /// Reconnect attempts made after a disconnect before giving up, unlimited if `None`
+    pub max_reconnect_attempts: Option<u32>,
+    /// Address sent a copy of every connection's traffic
+    pub tee: Option<String>,
+    /// Most local connections open at once, further ones wait their turn
+    pub max_concurrent: Option<usize>,
+    /// Bytes of each request and response reported back for the capture
+    /// store, or `None` when bodies aren't being captured
+    pub capture_limit: Option<usize>,
+}
+
+/// A registered connection to the tunnel server
+struct Session {
+    socket: WsStream,
+    url: String,
+    /// How the server agreed to write `Data` frames
+    framing: Framing,
+    /// Token for the data channel, if the server agreed to connect it lazily
+    data_token: Option<String>,
 }
 
 pub async fn start_tunnel(
-    local_port: u16,
-    domain: Option<String>,
-    server_port: u16,
-    client_id: String,
-    log_sender: mpsc::Sender<String>,
+    forwarding: Forwarding,
+    server_addr: String,
+    registration: Registration,
+    event_sender: mpsc::Sender<TunnelEvent>,
 ) -> Result<TunnelResult> {
-    // Start a detached process for the tunnel client
-    let mut cmd = Command::new("cargo");
-    cmd.args([
-        "run",
-        "--",
-        "--port",
-        &local_port.to_string(),
-        "--public-port",
-        &server_port.to_string(),
-    ]);
-
-    if let Some(domain) = &domain {
-        cmd.args(["--domain", domain]);
-    }
-
-    // Detach the process
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
-
-    // This would normally start the tunnel process, but for now we'll simulate it
-    let child = cmd
-        .spawn()
-        .context("Failed to start tunnel client process")?;
-
-    // Connect to the local server as if we were starting a standalone process
-    let server_url = format!("ws://localhost:{}/register", server_port);
-    let (mut socket, _) = connect_async(&server_url)
-        .await
-        .context("Failed to connect to tunnel server")?;
+    let session = register(&server_addr, &registration).await?;
+    let tunnel_url = session.url.clone();
+    let (drain_sender, drain) = watch::channel(false);
+
+    log(&event_sender, format!("Tunnel registered. URL: {}", tunnel_url)).await?;
+
+    // Forward requests over the same connection in the background
+    let task = tokio::spawn(async move {
+        let _ = handle_forwarding(session, registration, server_addr, forwarding, drain, event_sender)
+            .await;
+    });
+
+    Ok(TunnelResult {
+        url: tunnel_url,
+        process: None,
+        task: Some(task),
+        drain: Some(drain_sender),
+    })
+}
+
+/// Connects to the tunnel server and registers this client, returning the
+/// socket that carries both control and data messages along with the public URL
+async fn register(server_addr: &str, registration: &Registration) -> Result<Session> {
+    let mut socket = connect_server(
+        server_addr,
+        REGISTER_PATH,
+        None,
+        registration.tls.as_ref(),
+        registration.proxy.as_deref(),
+    )
+    .await?;
 
     // Send registration message
     let register_msg = TunnelMessage::Register {
-        client_id: client_id.clone(),
-        domain: domain.clone(),
+        client_id: registration.client_id.clone(),
+        instance_id: Some(registration.instance_id.clone()),
+        domain: registration.domain.clone(),
+        token: registration.token.clone(),
+        host_header: registration.host_header.clone(),
+        base_path: registration.base_path.clone(),
+        response_headers: registration.response_headers.clone(),
+        binary_data: true,
+        sequenced: true,
+        lazy_connect: registration.lazy_connect,
     };
 
     socket
-        .send(Message::Binary(serde_json::to_vec(&register_msg)?))
+        .send(Message::Binary(register_msg.encode(Framing::default())?))
         .await?;
 
     // Wait for response
-    let response = socket.next().await.context("No response from server")??;
-    let tunnel_message: TunnelMessage = serde_json::from_slice(&response.into_data())?;
+    let response = socket
+        .next()
+        .await
+        .ok_or_else(|| TunnelError::Protocol("no response".to_string()))??;
+    if let Message::Close(frame) = &response {
+        let reason = match frame {
+            Some(frame) if !frame.reason.is_empty() => {
+                format!("server closed the connection: {}", frame.reason)
+            }
+            _ => "server closed the connection".to_string(),
+        };
+        return Err(TunnelError::Protocol(reason).into());
+    }
+    let data = response.into_data();
+    let tunnel_message = TunnelMessage::decode(&data).map_err(|e| {
+        TunnelError::Protocol(format!("{} in {}", e, preview_frame(&data)))
+    })?;
+
+    match tunnel_message {
+        TunnelMessage::Registered {
+            url,
+            binary_data,
+            sequenced,
+            data_token,
+        } => Ok(Session {
+            socket,
+            url,
+            framing: Framing {
+                binary: binary_data,
+                sequenced,
+            },
+            data_token,
+        }),
+        TunnelMessage::Rejected {
+            reason,
+            retry_after,
+        } => Err(TunnelError::Rejected {
+            reason,
+            retry_after,
+        }
+        .into()),
+        other => Err(TunnelError::Protocol(format!("{:?}", other)).into()),
+    }
+}
+
+async fn handle_forwarding(
+    mut session: Session,
+    registration: Registration,
+    server_addr: String,
+    forwarding: Forwarding,
+    mut drain: watch::Receiver<bool>,
+    event_sender: mpsc::Sender<TunnelEvent>,
+) -> Result<()> {
+    loop {
+        let reason = match forward_session(
+            &mut session,
+            &server_addr,
+            &registration,
+            &forwarding,
+            &mut drain,
+            &event_sender,
+        )
+        .await
+        {
+            Ok(reason) => reason,
+            // Reconnecting would just be turned away again
+            Err(e) => match e.downcast::<TunnelError>() {
+                Ok(TunnelError::Deregistered { reason }) => {
+                    let reason = format!("Server ended the tunnel: {}", reason);
+                    log_error(&event_sender, reason.clone()).await?;
+                    event_sender.send(TunnelEvent::Ended { reason }).await?;
+                    return Ok(());
+                }
+                Ok(e) => e.to_string(),
+                Err(e) => e.to_string(),
+            },
+        };
+
+        // Stopped on purpose, there's nothing to reconnect
+        if *drain.borrow() {
+            return Ok(());
+        }
+
+        log_error(&event_sender, format!("Disconnected from server: {}", reason)).await?;
 
-    let tunnel_url = match tunnel_message {
-        TunnelMessage::Registered { url } => url,
-        _ => return Err(anyhow::anyhow!("Unexpected response from server")),
+        // Fail fast for scripts that would rather see the error than wait
+        if !forwarding.reconnect {
+            let reason = format!("Disconnected from server: {}", reason);
+            event_sender.send(TunnelEvent::Ended { reason }).await?;
+            return Ok(());
+        }
+
+        event_sender
+            .send(TunnelEvent::Disconnected { reason })
+            .await?;
+
+        // Keep trying to re-register until the server takes us back, or we
+        // run out of attempts
+        let mut delay = RECONNECT_DELAY;
+        let mut attempts = 0;
+        session = loop {
+            if let Some(max) = forwarding.max_reconnect_attempts {
+                if attempts >= max {
+                    let reason = format!(
+                        "Gave up after {} reconnect attempt{}",
+                        max,
+                        if max == 1 { "" } else { "s" }
+                    );
+                    log_error(&event_sender, reason.clone()).await?;
+                    event_sender.send(TunnelEvent::Ended { reason }).await?;
+                    return Ok(());
+                }
+            }
+            attempts += 1;
+
+            sleep(delay).await;
+            log(&event_sender, "Attempting to reconnect...").await?;
+            event_sender.send(TunnelEvent::Reconnecting).await?;
+
+            match register(&server_addr, &registration).await {
+                Ok(session) => {
+                    log(&event_sender, format!("Reconnected. URL: {}", session.url)).await?;
+                    break session;
+                }
+                Err(e) => {
+                    // Respect the server's retry hint if it turned us away
+                    delay = match e.downcast_ref::<TunnelError>() {
+                        Some(TunnelError::Rejected {
+                            retry_after: Some(seconds),
+                            ..
+                        }) => Duration::from_secs(*seconds),
+                        _ => RECONNECT_DELAY,
+                    };
+
+                    let reason = format!("Reconnect failed: {}", e);
+                    log_error(&event_sender, reason.clone()).await?;
+                    event_sender
+                        .send(TunnelEvent::Disconnected { reason })
+                        .await?;
+                }
+            }
+        };
+    }
+}
+
+/// Opens a WebSocket to `path` on the server, over mutual TLS when configured
+/// and authenticated with `bearer_token` if given
+async fn connect_server(
+    server_addr: &str,
+    path: &str,
+    bearer_token: Option<&str>,
+    tls: Option<&TlsConfig>,
+    proxy: Option<&str>,
+) -> Result<WsStream> {
+    let server_unreachable = |source| TunnelError::ServerUnreachable {
+        addr: server_addr.to_string(),
+        source,
+    };
+
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let mut request = format!("{}://{}{}", scheme, server_addr, path).into_client_request()?;
+    if let Some(token) = bearer_token {
+        request
+            .headers_mut()
+            .insert("Authorization", format!("Bearer {}", token).parse()?);
+    }
+
+    let proxy = Proxy::for_server(proxy, server_addr, tls.is_some())?;
+
+    let Some(tls) = tls else {
+        let (socket, _) = match &proxy {
+            Some(proxy) => {
+                let stream = proxy
+                    .connect(server_addr)
+                    .await
+                    .map_err(|e| server_unreachable(tungstenite::Error::Io(e)))?;
+                client_async(request, MaybeTlsStream::Plain(stream)).await
+            }
+            None => connect_async(request).await,
+        }
+        .map_err(server_unreachable)?;
+        return Ok(socket);
     };
 
-    log_sender
-        .send(format!("Tunnel registered. URL: {}", tunnel_url))
+    let host = server_addr
+        .rsplit_once(':')
+        .map_or(server_addr, |(host, _)| host);
+    let server_name = ServerName::try_from(tls.server_name.as_deref().unwrap_or(host))
+        .map_err(|e| TunnelError::Protocol(format!("invalid TLS server name: {}", e)))?
+        .to_owned();
+
+    let stream = match &proxy {
+        Some(proxy) => proxy.connect(server_addr).await,
+        None => TcpStream::connect(server_addr).await,
+    }
+    .map_err(|e| server_unreachable(tungstenite::Error::Io(e)))?;
+    let stream = tls::connector(tls)?
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| server_unreachable(tungstenite::Error::Io(e)))?;
+    let (socket, _) = client_async(request, MaybeTlsStream::Rustls(stream))
+        .await
+        .map_err(server_unreachable)?;
+
+    Ok(socket)
+}
+
+/// Prints the server's log lines as they happen until the server closes the stream
+pub async fn tail_server_logs(
+    server_addr: &str,
+    admin_token: &str,
+    tls: Option<&TlsConfig>,
+    proxy: Option<&str>,
+) -> Result<()> {
+    let mut socket =
+        connect_server(server_addr, ADMIN_LOGS_PATH, Some(admin_token), tls, proxy).await?;
+    println!("Streaming logs from {}", server_addr);
+
+    while let Some(message) = socket.next().await {
+        match message? {
+            Message::Text(line) => println!("{}", line),
+            Message::Ping(payload) => socket.send(Message::Pong(payload)).await?,
+            Message::Close(frame) => {
+                if let Some(frame) = frame.filter(|frame| !frame.reason.is_empty()) {
+                    println!("Server closed the log stream: {}", frame.reason);
+                }
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Connections to the local service opened on behalf of one session
+struct LocalConnections {
+    /// Writers to open local connections, keyed by the server's connection id
+    open: HashMap<u64, mpsc::Sender<Vec<u8>>>,
+    /// Copies of each connection's traffic on their way to the tee
+    tees: HashMap<u64, mpsc::Sender<Vec<u8>>>,
+    /// Whether the tee has already been reported unreachable
+    tee_unreachable: Arc<AtomicBool>,
+    /// Connections waiting for a slot under the concurrency cap, oldest
+    /// first, with the data that has arrived for them meanwhile
+    queued: VecDeque<(u64, Vec<Vec<u8>>)>,
+    /// Open connections whose request asked for an upgrade, e.g. to a
+    /// WebSocket. They can stay open indefinitely, so they don't take up a
+    /// slot under the concurrency cap.
+    upgraded: HashSet<u64>,
+}
+
+impl LocalConnections {
+    fn new() -> Self {
+        Self {
+            open: HashMap::new(),
+            tees: HashMap::new(),
+            tee_unreachable: Arc::new(AtomicBool::new(false)),
+            queued: VecDeque::new(),
+            upgraded: HashSet::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.open.is_empty() && self.queued.is_empty()
+    }
+
+    fn is_known(&self, connection_id: u64) -> bool {
+        self.open.contains_key(&connection_id)
+            || self.queued.iter().any(|(id, _)| *id == connection_id)
+    }
+
+    fn has_capacity(&self, forwarding: &Forwarding) -> bool {
+        forwarding
+            .max_concurrent
+            .is_none_or(|max| self.open.len() - self.upgraded.len() < max)
+    }
+
+    /// Drops a connection whether it is open or still waiting for a slot
+    fn remove(&mut self, connection_id: u64) {
+        // Dropping the writer shuts down our side of the local connection
+        self.open.remove(&connection_id);
+        self.tees.remove(&connection_id);
+        self.upgraded.remove(&connection_id);
+        self.queued.retain(|(id, _)| *id != connection_id);
+    }
+
+    /// Dials the local service for a new connection, starting its tee if
+    /// configured. `first` is the first data that arrived for it, holding the
+    /// start of the request.
+    async fn open(
+        &mut self,
+        connection_id: u64,
+        first: &[u8],
+        forwarding: &Forwarding,
+        allowlist: &mut EgressAllowlist,
+        outgoing: &mpsc::Sender<TunnelMessage>,
+        event_sender: &mpsc::Sender<TunnelEvent>,
+    ) -> Result<()> {
+        allowlist.ensure_resolved().await?;
+        let writer = open_local_connection(
+            allowlist,
+            &forwarding.local_target,
+            connection_id,
+            outgoing.clone(),
+            event_sender.clone(),
+        )
         .await?;
+        self.open.insert(connection_id, writer);
+        if http::header_value(first, "upgrade").is_some() {
+            self.upgraded.insert(connection_id);
+        }
 
-    // Start forwarding in the background
-    tokio::spawn(async move {
-        let _ = handle_forwarding(client_id, server_port, local_port, log_sender).await;
-    });
+        if let Some(addr) = &forwarding.tee {
+            self.tees.insert(
+                connection_id,
+                spawn_tee(addr.clone(), self.tee_unreachable.clone(), event_sender.clone()),
+            );
+        }
+        Ok(())
+    }
 
-    // Return as if the process is running
-    Ok(TunnelResult {
-        url: tunnel_url,
-        process: child,
-    })
+    /// Hands data from the server to an open connection and its tee
+    async fn write(&self, connection_id: u64, data: Vec<u8>) {
+        if let Some(tee) = self.tees.get(&connection_id) {
+            let _ = tee.try_send(data.clone());
+        }
+        if let Some(writer) = self.open.get(&connection_id) {
+            let _ = writer.send(data).await;
+        }
+    }
 }
 
-async fn handle_forwarding(
-    client_id: String,
-    server_port: u16,
-    local_port: u16,
-    log_sender: mpsc::Sender<String>,
+/// Answers a request on the local service's behalf and closes its connection
+async fn send_error_response(
+    socket: &mut WsStream,
+    data_channel: Option<&mpsc::Sender<Message>>,
+    connection_id: u64,
+    response: Vec<u8>,
+    framing: Framing,
 ) -> Result<()> {
-    // Connect to the server's data channel
-    let server_url = format!("ws://localhost:{}/data/{}", server_port, client_id);
-    let (mut socket, _) = connect_async(&server_url)
-        .await
-        .context("Failed to connect to tunnel data channel")?;
+    // Only ever sent before anything else for the connection
+    let response = TunnelMessage::Data {
+        connection_id,
+        seq: 0,
+        data: response,
+    };
+    send_data_frame(socket, data_channel, response.encode(framing)?).await?;
+    send_data_frame(
+        socket,
+        data_channel,
+        TunnelMessage::Close { connection_id }.encode(framing)?,
+    )
+    .await
+}
+
+/// Writes a `Data` or `Close` frame on the data channel, or on the tunnel
+/// socket when no data channel is open. The server reads both the same way.
+async fn send_data_frame(
+    socket: &mut WsStream,
+    data_channel: Option<&mpsc::Sender<Message>>,
+    frame: Vec<u8>,
+) -> Result<()> {
+    let frame = match data_channel {
+        Some(channel) => match channel.send(Message::Binary(frame)).await {
+            Ok(()) => return Ok(()),
+            Err(mpsc::error::SendError(frame)) => frame,
+        },
+        None => Message::Binary(frame),
+    };
+    socket.send(frame).await?;
+    Ok(())
+}
 
-    log_sender
-        .send("Connected to server data channel".to_string())
+/// Waits for the next frame from the server, on the tunnel socket or on
+/// whichever data channel is open
+async fn next_frame(
+    socket: &mut WsStream,
+    data_frames: &mut mpsc::Receiver<Vec<u8>>,
+) -> Option<Result<Message, tungstenite::Error>> {
+    tokio::select! {
+        msg = socket.next() => msg,
+        Some(data) = data_frames.recv() => Some(Ok(Message::Binary(data))),
+    }
+}
+
+/// Opens the data channel the server asked for and relays frames over it
+/// until either side closes it, handing the server's frames to `incoming`
+async fn run_data_channel(
+    server_addr: String,
+    registration: Registration,
+    token: String,
+    mut outgoing: mpsc::Receiver<Message>,
+    incoming: mpsc::Sender<Vec<u8>>,
+    event_sender: mpsc::Sender<TunnelEvent>,
+) -> Result<()> {
+    let mut socket = connect_server(
+        &server_addr,
+        DATA_CHANNEL_PATH,
+        None,
+        registration.tls.as_ref(),
+        registration.proxy.as_deref(),
+    )
+    .await?;
+    let attach = TunnelMessage::AttachDataChannel {
+        instance_id: registration.instance_id,
+        token,
+    };
+    socket
+        .send(Message::Binary(attach.encode(Framing::default())?))
         .await?;
+    log(&event_sender, "Opened data channel").await?;
+
+    loop {
+        tokio::select! {
+            frame = outgoing.recv() => {
+                let Some(frame) = frame else { break };
+                socket.send(frame).await?;
+            }
+            msg = socket.next() => {
+                let open = match msg {
+                    // The session has ended if nothing reads its frames
+                    Some(Ok(Message::Binary(data))) => incoming.send(data).await.is_ok(),
+                    Some(Ok(Message::Ping(payload))) => {
+                        socket.send(Message::Pong(payload)).await?;
+                        true
+                    }
+                    Some(Ok(Message::Close(_))) | None => false,
+                    Some(Err(e)) => return Err(e.into()),
+                    _ => true,
+                };
+                if !open {
+                    break;
+                }
+            }
+        }
+    }
+
+    log(&event_sender, "Data channel closed").await?;
+    Ok(())
+}
+
+/// Forwards requests arriving on the socket, or on the data channel of a
+/// lazily connected session, until the socket closes, returning the reason the
+/// connection ended
+async fn forward_session(
+    session: &mut Session,
+    server_addr: &str,
+    registration: &Registration,
+    forwarding: &Forwarding,
+    drain: &mut watch::Receiver<bool>,
+    event_sender: &mpsc::Sender<TunnelEvent>,
+) -> Result<String> {
+    let Session {
+        socket,
+        framing,
+        data_token,
+        ..
+    } = session;
+    let framing = *framing;
+    let mut allowlist = EgressAllowlist::resolve(forwarding.local_target.clone()).await;
+
+    // Local connections, open or waiting for a slot
+    let mut connections = LocalConnections::new();
+
+    // Messages produced by local connections, written to the socket in order
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<TunnelMessage>(100);
+
+    // When each connection started, and the request and response bytes seen so far
+    let mut stats: HashMap<u64, RequestStats> = HashMap::new();
+
+    // Frames from the server that arrived ahead of an earlier one, per connection
+    let mut reorder: HashMap<u64, Reorder> = HashMap::new();
+
+    // Set once asked to stop: no new connections, finish the open ones
+    let mut draining = false;
+
+    // Last connection counts reported to the UI
+    let mut reported_in_flight = (0, 0);
+
+    let mut heartbeat = tokio::time::interval(KEEPALIVE_INTERVAL);
+
+    // The data channel last opened at the server's request, which may have
+    // closed since, and the frames arriving on it
+    let mut data_channel: Option<mpsc::Sender<Message>> = None;
+    let (data_frames_tx, mut data_frames) = mpsc::channel::<Vec<u8>>(100);
 
     // Main loop
-    while let Some(msg) = socket.next().await {
-        match msg {
-            Ok(Message::Binary(data)) => {
-                match serde_json::from_slice::<TunnelMessage>(&data) {
-                    Ok(TunnelMessage::Data { data }) => {
-                        // Forward the data to the local service
-                        match TcpStream::connect(format!("127.0.0.1:{}", local_port)).await {
-                            Ok(mut local_stream) => {
-                                // Write the data to the local service
-                                if let Err(e) = local_stream.write_all(&data).await {
-                                    log_sender
-                                        .send(format!("Error writing to local service: {}", e))
-                                        .await?;
+    loop {
+        if draining && connections.is_empty() {
+            return Ok("Tunnel stopped".to_string());
+        }
+
+        // Start waiting connections as slots free up
+        while connections.has_capacity(forwarding) {
+            let Some((connection_id, frames)) = connections.queued.pop_front() else {
+                break;
+            };
+            let first = frames.first().map_or(&[][..], Vec::as_slice);
+            match connections
+                .open(connection_id, first, forwarding, &mut allowlist, &outgoing_tx, event_sender)
+                .await
+            {
+                Ok(()) => {
+                    for data in frames {
+                        connections.write(connection_id, data).await;
+                    }
+                }
+                Err(e) => {
+                    let trace = stats.remove(&connection_id).and_then(|entry| entry.trace);
+                    reorder.remove(&connection_id);
+                    log_error(event_sender, traced(trace.as_deref(), format!("{:#}", e))).await?;
+                    let response = http::bad_gateway(&format!("{}. Is it running?", e));
+                    send_error_response(socket, data_channel.as_ref(), connection_id, response, framing).await?;
+                }
+            }
+        }
+
+        let in_flight = (connections.open.len(), connections.queued.len());
+        if in_flight != reported_in_flight {
+            reported_in_flight = in_flight;
+            event_sender
+                .send(TunnelEvent::InFlight {
+                    active: in_flight.0,
+                    queued: in_flight.1,
+                })
+                .await?;
+        }
+
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                socket
+                    .send(Message::Binary(TunnelMessage::KeepAlive.encode(framing)?))
+                    .await?;
+            }
+            _ = drain.changed(), if !draining => {
+                draining = true;
+                if !connections.is_empty() {
+                    log(
+                        event_sender,
+                        format!(
+                            "Waiting for {} in-flight connection(s) to finish",
+                            connections.open.len() + connections.queued.len()
+                        ),
+                    )
+                    .await?;
+                }
+            }
+            Some(message) = outgoing_rx.recv() => {
+                match &message {
+                    TunnelMessage::Data { connection_id, data, .. } => {
+                        if let Some(entry) = stats.get_mut(connection_id) {
+                            if entry.response_bytes == 0 {
+                                entry.status = http::status_code(data);
+                            }
+                            entry.response_bytes += data.len();
+                            if let Some(limit) = forwarding.capture_limit {
+                                keep_prefix(&mut entry.response, data, limit);
+                            }
+                        }
+                        if let Some(tee) = connections.tees.get(connection_id) {
+                            let _ = tee.try_send(data.clone());
+                        }
+                    }
+                    TunnelMessage::Close { connection_id } => {
+                        connections.remove(*connection_id);
+                        reorder.remove(connection_id);
+                        if let Some(entry) = stats.remove(connection_id) {
+                            if forwarding.capture_limit.is_some() {
+                                event_sender
+                                    .send(TunnelEvent::Captured {
+                                        trace: entry.trace.clone(),
+                                        request: entry.request,
+                                        response: entry.response,
+                                    })
+                                    .await?;
+                            }
+                            event_sender
+                                .send(TunnelEvent::RequestCompleted {
+                                    request_bytes: entry.request_bytes,
+                                    response_bytes: entry.response_bytes,
+                                    duration: entry.started.elapsed(),
+                                    trace: entry.trace,
+                                    request_line: entry.request_line,
+                                    status: entry.status,
+                                })
+                                .await?;
+                        }
+                    }
+                    _ => {}
+                }
+                send_data_frame(socket, data_channel.as_ref(), message.encode(framing)?).await?;
+            }
+            msg = next_frame(socket, &mut data_frames) => {
+                let Some(msg) = msg else { break };
+                match msg {
+                    Ok(Message::Binary(data)) => {
+                        match TunnelMessage::decode(&data) {
+                            Ok(TunnelMessage::Data { connection_id, seq, data }) => {
+                                // Hold back frames that overtook an earlier one
+                                let data = if framing.sequenced {
+                                    let pushed = reorder.entry(connection_id).or_default().push(seq, data);
+                                    match pushed {
+                                        Ok(Some(data)) => data,
+                                        Ok(None) => continue,
+                                        Err(e) => {
+                                            let trace = stats.remove(&connection_id).and_then(|entry| entry.trace);
+                                            log_error(
+                                                event_sender,
+                                                traced(trace.as_deref(), format!("Dropping connection: {}", e)),
+                                            )
+                                            .await?;
+                                            reorder.remove(&connection_id);
+                                            connections.remove(connection_id);
+                                            send_data_frame(
+                                                socket,
+                                                data_channel.as_ref(),
+                                                TunnelMessage::Close { connection_id }.encode(framing)?,
+                                            )
+                                            .await?;
+                                            continue;
+                                        }
+                                    }
+                                } else {
+                                    data
+                                };
+
+                                let entry = stats.entry(connection_id).or_insert_with(|| RequestStats {
+                                    started: Instant::now(),
+                                    request_bytes: 0,
+                                    response_bytes: 0,
+                                    trace: http::header_value(&data, TRACE_HEADER),
+                                    request_line: http::request_summary(&data),
+                                    status: None,
+                                    request: Vec::new(),
+                                    response: Vec::new(),
+                                });
+                                entry.request_bytes += data.len();
+                                if let Some(limit) = forwarding.capture_limit {
+                                    keep_prefix(&mut entry.request, &data, limit);
+                                }
+                                let trace = entry.trace.clone();
+
+                                // Still waiting for a slot, hold on to the data
+                                if let Some((_, frames)) = connections
+                                    .queued
+                                    .iter_mut()
+                                    .find(|(id, _)| *id == connection_id)
+                                {
+                                    frames.push(data);
                                     continue;
                                 }
 
-                                // Read the response from the local service
-                                let mut buffer = vec![0; 8192];
-                                match local_stream.read(&mut buffer).await {
-                                    Ok(n) if n > 0 => {
-                                        buffer.truncate(n);
-
-                                        // Send the response back to the server
-                                        let response = TunnelMessage::Data { data: buffer };
-                                        socket
-                                            .send(Message::Binary(serde_json::to_vec(
-                                                &response,
-                                            )?))
+                                if !connections.is_known(connection_id) {
+                                    if draining {
+                                        stats.remove(&connection_id);
+                                        reorder.remove(&connection_id);
+                                        let response = http::service_unavailable("Tunnel is shutting down");
+                                        send_error_response(socket, data_channel.as_ref(), connection_id, response, framing)
                                             .await?;
+                                        continue;
                                     }
-                                    Ok(_) => {
-                                        log_sender
-                                            .send(
-                                                "Local service closed the connection"
-                                                    .to_string(),
-                                            )
-                                            .await?;
+
+                                    if !connections.has_capacity(forwarding) {
+                                        connections.queued.push_back((connection_id, vec![data]));
+                                        continue;
                                     }
-                                    Err(e) => {
-                                        log_sender
-                                            .send(format!(
-                                                "Error reading from local service: {}",
-                                                e
-                                            ))
+
+                                    if let Err(e) = connections
+                                        .open(connection_id, &data, forwarding, &mut allowlist, &outgoing_tx, event_sender)
+                                        .await
+                                    {
+                                        log_error(event_sender, traced(trace.as_deref(), format!("{:#}", e))).await?;
+
+                                        // Answer straight away rather than leaving the caller to time out
+                                        stats.remove(&connection_id);
+                                        reorder.remove(&connection_id);
+                                        let response = http::bad_gateway(&format!("{}. Is it running?", e));
+                                        send_error_response(socket, data_channel.as_ref(), connection_id, response, framing)
                                             .await?;
+                                        continue;
+                                    }
+                                }
+
+                                // Forward the data to the local service
+                                connections.write(connection_id, data).await;
+                            }
+                            Ok(TunnelMessage::Close { connection_id }) => {
+                                connections.remove(connection_id);
+                                if let Some(state) = reorder.remove(&connection_id) {
+                                    if state.pending() > 0 {
+                                        log_error(
+                                            event_sender,
+                                            format!(
+                                                "Connection {} closed with {} frame(s) still out of order",
+                                                connection_id,
+                                                state.pending()
+                                            ),
+                                        )
+                                        .await?;
                                     }
                                 }
                             }
+                            Ok(TunnelMessage::OpenDataChannel) => {
+                                let Some(token) = data_token.clone() else {
+                                    log(event_sender, "Server asked for a data channel this session doesn't use").await?;
+                                    continue;
+                                };
+                                let (channel, outgoing) = mpsc::channel::<Message>(100);
+                                data_channel = Some(channel);
+                                let opened = run_data_channel(
+                                    server_addr.to_string(),
+                                    registration.clone(),
+                                    token,
+                                    outgoing,
+                                    data_frames_tx.clone(),
+                                    event_sender.clone(),
+                                );
+                                let event_sender = event_sender.clone();
+                                tokio::spawn(async move {
+                                    if let Err(e) = opened.await {
+                                        let _ = log_error(&event_sender, format!("Data channel failed: {:#}", e)).await;
+                                    }
+                                });
+                            }
+                            Ok(TunnelMessage::Quota { remaining }) => {
+                                event_sender.send(TunnelEvent::Quota { remaining }).await?;
+                            }
+                            Ok(TunnelMessage::Deregister { reason }) => {
+                                return Err(TunnelError::Deregistered { reason }.into());
+                            }
+                            // The server acknowledging our heartbeat; answering
+                            // it would start an endless exchange
+                            Ok(TunnelMessage::KeepAlive) => {}
+                            Ok(TunnelMessage::Error { message }) => {
+                                log_error(event_sender, format!("Server reported an error: {}", message)).await?;
+                            }
                             Err(e) => {
-                                log_sender
-                                    .send(format!("Failed to connect to local service: {}", e))
-                                    .await?;
+                                log_error(
+                                    event_sender,
+                                    format!("Malformed message from server: {} ({})", e, preview_frame(&data)),
+                                )
+                                .await?;
+                            }
+                            _ => {
+                                log(event_sender, "Received unknown message type").await?;
                             }
                         }
                     }
-                    Ok(TunnelMessage::KeepAlive) => {
-                        // Send keep-alive response
-                        socket
-                            .send(Message::Binary(serde_json::to_vec(
-                                &TunnelMessage::KeepAlive,
-                            )?))
-                            .await?;
+                    // Answer protocol pings from the server or a proxy in
+                    // between, which may drop the connection otherwise
+                    Ok(Message::Ping(payload)) => {
+                        socket.send(Message::Pong(payload)).await?;
+                    }
+                    Ok(Message::Close(_)) => {
+                        return Ok("Server closed the connection".to_string());
                     }
-                    _ => {
-                        log_sender
-                            .send("Received unknown message type".to_string())
-                            .await?;
+                    Err(e) => {
+                        return Ok(format!("WebSocket error: {}", e));
                     }
+                    _ => {}
                 }
             }
-            Ok(Message::Close(_)) => {
-                log_sender
-                    .send("Server closed the connection".to_string())
-                    .await?;
+        }
+    }
+
+    Ok("Connection ended".to_string())
+}
+
+/// Connects to the local service for one public connection and relays its
+/// output back as `Data` frames, returning the channel that writes to it
+async fn open_local_connection(
+    allowlist: &EgressAllowlist,
+    local_target: &LocalTarget,
+    connection_id: u64,
+    outgoing: mpsc::Sender<TunnelMessage>,
+    event_sender: mpsc::Sender<TunnelEvent>,
+) -> Result<mpsc::Sender<Vec<u8>>> {
+    let unavailable = |source| TunnelError::LocalUnavailable {
+        target: local_target.to_string(),
+        source,
+    };
+    let addrs = local_target.resolve().await.map_err(unavailable)?;
+
+    // Only ever dial where the configured local target pointed
+    allowlist.check(&addrs)?;
+    let (mut local_reader, mut local_writer) = local_target.connect(&addrs).await.map_err(unavailable)?;
+
+    let (writer_tx, mut writer_rx) = mpsc::channel::<Vec<u8>>(32);
+
+    // Write data from the server to the local service
+    let writer_events = event_sender.clone();
+    tokio::spawn(async move {
+        while let Some(data) = writer_rx.recv().await {
+            if let Err(e) = local_writer.write_all(&data).await {
+                let _ = log_error(&writer_events, format!("Error writing to local service: {}", e)).await;
                 break;
             }
+        }
+    });
+
+    // Read the response from the local service until it closes
+    tokio::spawn(async move {
+        let mut buffer = vec![0; 8192];
+        let mut seq = 0;
+        loop {
+            match local_reader.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    let message = TunnelMessage::Data {
+                        connection_id,
+                        seq,
+                        data: buffer[..n].to_vec(),
+                    };
+                    seq += 1;
+                    if outgoing.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let _ = log_error(&event_sender, format!("Error reading from local service: {}", e)).await;
+                    break;
+                }
+            }
+        }
+
+        // Answer for the local service rather than leaving the caller to
+        // guess why nothing came back
+        if seq == 0 {
+            let message = "Local service closed the connection without responding";
+            let _ = log_error(&event_sender, message).await;
+            let _ = outgoing
+                .send(TunnelMessage::Data {
+                    connection_id,
+                    seq,
+                    data: http::bad_gateway(message),
+                })
+                .await;
+        }
+
+        let _ = outgoing.send(TunnelMessage::Close { connection_id }).await;
+    });
+
+    Ok(writer_tx)
+}
+
+/// Mirrors one connection's request and response bytes, in the order they
+/// pass through, to a separate TCP connection to the tee address. Runs off the
+/// forwarding path: copies are dropped rather than waited on, and anything the
+/// tee sends back is ignored.
+fn spawn_tee(
+    addr: String,
+    unreachable: Arc<AtomicBool>,
+    event_sender: mpsc::Sender<TunnelEvent>,
+) -> mpsc::Sender<Vec<u8>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(TEE_BUFFER);
+    tokio::spawn(async move {
+        let mut stream = match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                unreachable.store(false, Ordering::Relaxed);
+                stream
+            }
             Err(e) => {
-                log_sender.send(format!("WebSocket error: {}", e)).await?;
+                // Report it once rather than for every connection
+                if !unreachable.swap(true, Ordering::Relaxed) {
+                    let _ = log_error(&event_sender, format!("Tee {} unreachable: {}", addr, e)).await;
+                }
+                return;
+            }
+        };
+
+        while let Some(data) = rx.recv().await {
+            if stream.write_all(&data).await.is_err() {
                 break;
             }
-            _ => {}
+        }
+    });
+    tx
+}
+
+async fn log(event_sender: &mpsc::Sender<TunnelEvent>, message: impl Into<String>) -> Result<()> {
+    event_sender.send(TunnelEvent::Log(message.into())).await?;
+    Ok(())
+}
+
+async fn log_error(event_sender: &mpsc::Sender<TunnelEvent>, message: impl Into<String>) -> Result<()> {
+    event_sender.send(TunnelEvent::Error(message.into())).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist() -> EgressAllowlist {
+        EgressAllowlist {
+            target: LocalTarget::Tcp("localhost".to_string(), 3000),
+            addrs: Some(vec!["127.0.0.1:3000".parse().unwrap(), "[::1]:3000".parse().unwrap()]),
         }
     }
 
-    log_sender
-        .send("Disconnected from server".to_string())
-        .await?;
+    #[test]
+    fn egress_allows_the_resolved_target() {
+        let allowlist = allowlist();
+        assert!(allowlist.check(&["127.0.0.1:3000".parse().unwrap()]).is_ok());
+        assert!(allowlist
+            .check(&["[::1]:3000".parse().unwrap(), "127.0.0.1:3000".parse().unwrap()])
+            .is_ok());
+    }
 
-    // Try to reconnect after a delay
-    sleep(Duration::from_secs(5)).await;
-    log_sender
-        .send("Attempting to reconnect...".to_string())
-        .await?;
+    #[test]
+    fn egress_refuses_other_destinations() {
+        let allowlist = allowlist();
+        assert!(allowlist.check(&["10.0.0.5:3000".parse().unwrap()]).is_err());
+        assert!(allowlist.check(&["127.0.0.1:22".parse().unwrap()]).is_err());
+        // One bad address among good ones is enough to refuse
+        assert!(allowlist
+            .check(&["127.0.0.1:3000".parse().unwrap(), "169.254.169.254:80".parse().unwrap()])
+            .is_err());
+    }
 
-    // This would normally try to reconnect, but for the example we'll just return
-    Ok(())
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn egress_resolves_again_after_a_failed_lookup() {
+        let mut allowlist = EgressAllowlist {
+            target: LocalTarget::Tcp("127.0.0.1".to_string(), 3000),
+            addrs: None,
+        };
+        allowlist.ensure_resolved().await.unwrap();
+        assert!(allowlist.check(&["127.0.0.1:3000".parse().unwrap()]).is_ok());
+    }
+
+    #[test]
+    fn egress_allows_unix_sockets() {
+        let allowlist = EgressAllowlist {
+            target: LocalTarget::Unix(PathBuf::from("/tmp/app.sock")),
+            addrs: Some(Vec::new()),
+        };
+        assert!(allowlist.check(&[]).is_ok());
+    }
+}
@@ -1,42 +1,52 @@
 use anyhow::{Context, Result};
 use tokio::process::Command;
-use tokio::io::{BufReader, AsyncBufReadExt};
+use tokio::io::BufReader;
 use std::process::Stdio;
 use regex::Regex;
 
 use crate::app::App;
-use super::TunnelResult;
+use super::{detect_provider_error, url_timeout, LossyLines, Provider, TunnelError, TunnelResult, URL_DETECTION_TIMEOUT};
 
-pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> Result<TunnelResult> {
+pub async fn start_tunnel(
+    port: u16,
+    domain: Option<String>,
+    extra_args: Vec<String>,
+    app: &mut App,
+) -> Result<TunnelResult> {
     app.add_log("Starting cloudflared tunnel...");
     
     // Check if cloudflared is installed
-    let status = Command::new("which")
-        .arg("cloudflared")
-        .status()
-        .await
-        .context("Failed to check if cloudflared is installed")?;
-    
-    if !status.success() {
-        app.add_log("Error: cloudflared not found. Please install it first.");
+    if !Provider::Cloudflared.is_installed() {
+        app.add_error("Error: cloudflared not found. Please install it first.");
         app.add_log("Installation instructions: https://developers.cloudflare.com/cloudflare-one/connections/connect-apps/install-and-setup/installation");
-        return Err(anyhow::anyhow!("cloudflared not installed"));
+        return Err(TunnelError::ProviderNotInstalled { provider: Provider::Cloudflared.name() }.into());
     }
     
     // Prepare the command
     let mut cmd = Command::new("cloudflared");
-    cmd.args(["tunnel", "--url", &format!("http://localhost:{}", port)]);
+    let scheme = if app.local_https { "https" } else { "http" };
+    cmd.args(["tunnel", "--url", &format!("{}://localhost:{}", scheme, port)]);
+    if app.local_https {
+        // Local dev certificates are usually self-signed
+        cmd.arg("--no-tls-verify");
+    }
     
     // Add custom domain if provided
-    if let Some(domain) = domain {
-        cmd.args(["--hostname", &domain]);
+    if let Some(domain) = &domain {
+        cmd.args(["--hostname", domain]);
     }
     
+    // Pass through any user supplied flags
+    cmd.args(&extra_args);
+    
     // stdout and stderr will be captured so we can parse the URL
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
     
-    app.add_log(&format!("Running command: cloudflared tunnel --url http://localhost:{}", port));
+    app.add_log(&format!("Running command: cloudflared tunnel --url {}://localhost:{}", scheme, port));
+    if !extra_args.is_empty() {
+        app.add_log(&format!("Extra cloudflared arguments: {}", extra_args.join(" ")));
+    }
     
     // Start the process
     let mut child = cmd.spawn().context("Failed to start cloudflared process")?;
@@ -46,8 +56,8 @@ pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> R
     let stderr = child.stderr.take().expect("Failed to capture stderr");
     
     // Create readers
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+    let mut stdout_reader = LossyLines::new(BufReader::new(stdout));
+    let mut stderr_reader = LossyLines::new(BufReader::new(stderr));
     
     // Regex to extract the URL
     let url_regex = Regex::new(r"https://[^/\s]+").unwrap();
@@ -55,28 +65,44 @@ pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> R
     // This will store the tunnel URL once we find it
     let mut tunnel_url = String::new();
     
-    // Process stdout and stderr lines to find the URL
-    let stdout_future = async {
-        while let Some(line) = stdout_reader.next_line().await.unwrap_or(None) {
-            app.add_log(&format!("cloudflared: {}", line));
-            
-            // Try to find the tunnel URL
-            if tunnel_url.is_empty() {
+    // Give up if the provider never reports a URL
+    let deadline = tokio::time::sleep(URL_DETECTION_TIMEOUT);
+    tokio::pin!(deadline);
+    
+    // Process stdout and stderr lines until we find the URL or stdout closes
+    loop {
+        tokio::select! {
+            _ = &mut deadline => {
+                return Err(url_timeout(Provider::Cloudflared, &mut child, app).await);
+            }
+            line = stdout_reader.next_line() => {
+                let Some(line) = line.unwrap_or(None) else { break };
+                app.add_log(&format!("cloudflared: {}", line));
+                
+                if let Some(error) = detect_provider_error(Provider::Cloudflared, &line) {
+                    app.add_error(&format!("Error: {}", error));
+                    let _ = child.kill().await;
+                    return Err(error.into());
+                }
+                
+                // Try to find the tunnel URL
                 if let Some(url_match) = url_regex.find(&line) {
                     tunnel_url = url_match.as_str().to_string();
                     app.add_log(&format!("Tunnel URL found: {}", tunnel_url));
                     break;
                 }
             }
-        }
-    };
-    
-    let stderr_future = async {
-        while let Some(line) = stderr_reader.next_line().await.unwrap_or(None) {
-            app.add_log(&format!("cloudflared error: {}", line));
-            
-            // Try to find the tunnel URL in stderr too (sometimes it's there)
-            if tunnel_url.is_empty() {
+            line = stderr_reader.next_line() => {
+                let Some(line) = line.unwrap_or(None) else { break };
+                app.add_error(&format!("cloudflared error: {}", line));
+                
+                if let Some(error) = detect_provider_error(Provider::Cloudflared, &line) {
+                    app.add_error(&format!("Error: {}", error));
+                    let _ = child.kill().await;
+                    return Err(error.into());
+                }
+                
+                // Try to find the tunnel URL in stderr too (sometimes it's there)
                 if let Some(url_match) = url_regex.find(&line) {
                     tunnel_url = url_match.as_str().to_string();
                     app.add_log(&format!("Tunnel URL found: {}", tunnel_url));
@@ -84,12 +110,6 @@ pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> R
                 }
             }
         }
-    };
-    
-    // Run both futures concurrently
-    tokio::select! {
-        _ = stdout_future => {},
-        _ = stderr_future => {},
     }
     
     // If we couldn't find a URL, use a default format
@@ -106,6 +126,8 @@ pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> R
     
     Ok(TunnelResult {
         url: tunnel_url,
-        process: child,
+        process: Some(child),
+        task: None,
+        drain: None,
     })
 }
\ No newline at end of file
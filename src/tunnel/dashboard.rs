@@ -0,0 +1,204 @@
+use anyhow::Result;
+use crossterm::{
+    event::{self, Event, KeyCode},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+
+use crate::ui::format_bytes;
+
+/// How often the table is redrawn with fresh numbers
+pub const TICK_RATE: Duration = Duration::from_millis(500);
+
+/// Server log lines kept below the table
+const LOG_LINES: usize = 200;
+
+/// One connected client as shown in the dashboard
+#[derive(Clone, Debug)]
+pub struct ClientSummary {
+    pub client_id: String,
+    pub domain: String,
+    pub connected_for: Duration,
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+/// Column the table is ordered by
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortBy {
+    ClientId,
+    Domain,
+    Connected,
+    Requests,
+    Bytes,
+}
+
+impl SortBy {
+    const ALL: [SortBy; 5] = [
+        SortBy::ClientId,
+        SortBy::Domain,
+        SortBy::Connected,
+        SortBy::Requests,
+        SortBy::Bytes,
+    ];
+
+    fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|sort| *sort == self).unwrap();
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// Names sort alphabetically, numbers largest first
+    fn sort(self, clients: &mut [ClientSummary]) {
+        match self {
+            SortBy::ClientId => clients.sort_by(|a, b| a.client_id.cmp(&b.client_id)),
+            SortBy::Domain => clients.sort_by(|a, b| a.domain.cmp(&b.domain)),
+            SortBy::Connected => clients.sort_by_key(|client| Reverse(client.connected_for)),
+            SortBy::Requests => clients.sort_by_key(|client| Reverse(client.requests)),
+            SortBy::Bytes => clients.sort_by_key(|client| Reverse(client.bytes)),
+        }
+    }
+}
+
+/// Shows connected clients and the server's log until `q` is pressed
+pub async fn run(
+    mut clients: watch::Receiver<Vec<ClientSummary>>,
+    mut logs: broadcast::Receiver<String>,
+) -> Result<()> {
+    crate::install_panic_hook();
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let mut sort_by = SortBy::Connected;
+    let mut log_lines = VecDeque::new();
+    let result = loop {
+        while let Ok(line) = logs.try_recv() {
+            if log_lines.len() == LOG_LINES {
+                log_lines.pop_front();
+            }
+            log_lines.push_back(line);
+        }
+        let mut snapshot = clients.borrow_and_update().clone();
+        sort_by.sort(&mut snapshot);
+
+        if let Err(e) = terminal.draw(|f| draw(f, &snapshot, sort_by, &log_lines)) {
+            break Err(e.into());
+        }
+
+        match event::poll(TICK_RATE) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => break Err(e.into()),
+        }
+        match event::read() {
+            Ok(Event::Key(key)) => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+                KeyCode::Char('s') => sort_by = sort_by.next(),
+                _ => {}
+            },
+            Ok(Event::Resize(_, _)) => {
+                if let Err(e) = terminal.autoresize() {
+                    break Err(e.into());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+fn draw(f: &mut Frame, clients: &[ClientSummary], sort_by: SortBy, logs: &VecDeque<String>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints([
+            Constraint::Percentage(60),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .split(f.size());
+
+    let header = Row::new(SortBy::ALL.iter().map(|column| {
+        let name = match column {
+            SortBy::ClientId => "Client",
+            SortBy::Domain => "Domain",
+            SortBy::Connected => "Connected",
+            SortBy::Requests => "Requests",
+            SortBy::Bytes => "Bytes",
+        };
+        if *column == sort_by {
+            format!("{} ▼", name)
+        } else {
+            name.to_string()
+        }
+    }))
+    .style(Style::default().add_modifier(Modifier::BOLD));
+
+    let rows = clients.iter().map(|client| {
+        Row::new(vec![
+            client.client_id.chars().take(8).collect::<String>(),
+            client.domain.clone(),
+            format_duration(client.connected_for),
+            client.requests.to_string(),
+            format_bytes(client.bytes as usize),
+        ])
+    });
+
+    let widths = [
+        Constraint::Length(10),
+        Constraint::Min(20),
+        Constraint::Length(11),
+        Constraint::Length(10),
+        Constraint::Length(10),
+    ];
+    let table = Table::new(rows, widths).header(header).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!("Clients ({})", clients.len())),
+    );
+    f.render_widget(table, chunks[0]);
+
+    // Leave room for the borders
+    let height = chunks[1].height.saturating_sub(2) as usize;
+    let lines: Vec<ListItem> = logs
+        .iter()
+        .skip(logs.len().saturating_sub(height))
+        .map(|line| ListItem::new(line.as_str()))
+        .collect();
+    let log_list = List::new(lines).block(Block::default().borders(Borders::ALL).title("Logs"));
+    f.render_widget(log_list, chunks[1]);
+
+    let help = Paragraph::new(" [s] Change sort column  [q] Stop server")
+        .style(Style::default().fg(Color::DarkGray));
+    f.render_widget(help, chunks[2]);
+}
+
+/// Formats how long a client has been connected, e.g. `1h 05m` or `42s`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
@@ -0,0 +1,87 @@
+use tokio_tungstenite::tungstenite;
+
+/// Failures the client and server distinguish between, so callers can react
+/// to the kind of error rather than its message
+#[derive(Debug, thiserror::Error)]
+pub enum TunnelError {
+    #[error("Port {port} is already in use")]
+    PortInUse { port: u16 },
+
+    #[error("Could not reach the tunnel server at {addr}")]
+    ServerUnreachable {
+        addr: String,
+        #[source]
+        source: tungstenite::Error,
+    },
+
+    #[error("None of the configured tunnel servers are reachable")]
+    NoServerReachable,
+
+    /// Registration refused by the server, e.g. because it is full or draining
+    #[error("Server rejected the tunnel: {reason}")]
+    Rejected {
+        reason: String,
+        /// Seconds the server asked us to wait before trying again
+        retry_after: Option<u64>,
+    },
+
+    /// The server ended the session, e.g. because its traffic cap was reached
+    #[error("Server ended the tunnel: {reason}")]
+    Deregistered { reason: String },
+
+    #[error("Unexpected response from server: {0}")]
+    Protocol(String),
+
+    #[error("{provider} not installed")]
+    ProviderNotInstalled { provider: &'static str },
+
+    #[error("{provider}: {message}")]
+    AuthFailed {
+        provider: &'static str,
+        message: &'static str,
+    },
+
+    #[error("{provider}: {message}")]
+    ProviderFailed {
+        provider: &'static str,
+        message: &'static str,
+    },
+
+    #[error("Timed out waiting for {provider} to report a tunnel URL")]
+    UrlTimeout { provider: &'static str },
+
+    #[error("Failed to connect to local service at {target}")]
+    LocalUnavailable {
+        target: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl TunnelError {
+    /// What the user can do about the error, if there's anything obvious
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            TunnelError::PortInUse { .. } => Some("stop whatever is using it or pass --fallback-port"),
+            TunnelError::ServerUnreachable { .. } | TunnelError::NoServerReachable => {
+                Some("check the server is running and --server-host/--public-port are right")
+            }
+            TunnelError::ProviderNotInstalled { .. } => Some("install it or press [o] to pick another provider"),
+            TunnelError::AuthFailed { .. } => Some("check the provider's credentials"),
+            TunnelError::LocalUnavailable { .. } => Some("make sure your application is running"),
+            _ => None,
+        }
+    }
+
+    /// Whether trying again later, without changing anything, might succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TunnelError::ServerUnreachable { .. }
+                | TunnelError::NoServerReachable
+                | TunnelError::Rejected { .. }
+                | TunnelError::UrlTimeout { .. }
+                | TunnelError::LocalUnavailable { .. }
+        )
+    }
+}
@@ -0,0 +1,251 @@
+/// A response read back from the local service
+#[derive(Clone)]
+pub struct ParsedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Trailing headers sent after a chunked body, e.g. `grpc-status`
+    pub trailers: Trailers,
+}
+
+/// Headers describing the connection to the local service rather than the
+/// response itself; the public listener sets its own
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "content-length",
+];
+
+/// Length of the response head including its terminating blank line, once
+/// all of it has arrived
+pub fn head_len(raw: &[u8]) -> Option<usize> {
+    find(raw, b"\r\n\r\n").map(|end| end + 4)
+}
+
+/// Value of header `name` in the head of a raw HTTP/1.x message, if the
+/// head has fully arrived and contains it
+pub fn header_value(raw: &[u8], name: &str) -> Option<String> {
+    let head_end = find(raw, b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&raw[..head_end]).ok()?;
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (line_name, value) = line.split_once(':')?;
+        line_name
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// Method and path of a raw HTTP/1.x request, leaving out the query string
+/// since it may carry credentials, once the request line has arrived
+pub fn request_summary(raw: &[u8]) -> Option<String> {
+    let line = first_line(raw)?;
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    let path = target.split('?').next().unwrap_or(target);
+    Some(format!("{} {}", method, path))
+}
+
+/// Status code of a raw HTTP/1.x response, once the status line has arrived
+pub fn status_code(raw: &[u8]) -> Option<u16> {
+    first_line(raw)?
+        .strip_prefix("HTTP/")?
+        .split(' ')
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+fn first_line(raw: &[u8]) -> Option<&str> {
+    let end = find(raw, b"\r\n")?;
+    std::str::from_utf8(&raw[..end]).ok()
+}
+
+/// Parses a raw HTTP/1.x response, decoding a chunked body if necessary.
+/// Returns `None` if the bytes aren't a well-formed response.
+pub fn parse_response(raw: &[u8]) -> Option<ParsedResponse> {
+    let head_end = find(raw, b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&raw[..head_end]).ok()?;
+    let body = &raw[head_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next()?;
+    if !status_line.starts_with("HTTP/") {
+        return None;
+    }
+    let status = status_line.split_whitespace().nth(1)?.parse::<u16>().ok()?;
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let chunked = headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("transfer-encoding") && value.to_lowercase().contains("chunked")
+    });
+    let (body, trailers) = if chunked {
+        decode_chunked(body)?
+    } else {
+        (body.to_vec(), Vec::new())
+    };
+
+    headers.retain(|(name, _)| !is_hop_by_hop(name));
+
+    Some(ParsedResponse {
+        status,
+        headers,
+        body,
+        trailers,
+    })
+}
+
+/// Header fields sent after a chunked body, as name and value
+pub type Trailers = Vec<(String, String)>;
+
+/// Decodes a `Transfer-Encoding: chunked` body, returning it along with any trailers
+fn decode_chunked(mut body: &[u8]) -> Option<(Vec<u8>, Trailers)> {
+    let mut decoded = Vec::new();
+
+    loop {
+        let line_end = find(body, b"\r\n")?;
+        let size_line = std::str::from_utf8(&body[..line_end]).ok()?;
+        // Ignore chunk extensions
+        let size_hex = size_line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_hex, 16).ok()?;
+        body = &body[line_end + 2..];
+
+        if size == 0 {
+            return Some((decoded, parse_trailers(body)?));
+        }
+
+        if body.len() < size + 2 {
+            return None;
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+/// Parses the trailer section following the last chunk, up to the blank line
+/// ending it. Peers may close the connection without sending that line.
+fn parse_trailers(mut section: &[u8]) -> Option<Trailers> {
+    let mut trailers = Vec::new();
+
+    loop {
+        if section.is_empty() {
+            return Some(trailers);
+        }
+        let line_end = find(section, b"\r\n")?;
+        if line_end == 0 {
+            return Some(trailers);
+        }
+
+        let line = std::str::from_utf8(&section[..line_end]).ok()?;
+        let (name, value) = line.split_once(':')?;
+        if !is_hop_by_hop(name.trim()) {
+            trailers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        section = &section[line_end + 2..];
+    }
+}
+
+/// A raw `502 Bad Gateway` response, sent back when the local service
+/// couldn't produce one itself
+pub fn bad_gateway(message: &str) -> Vec<u8> {
+    plain_response("502 Bad Gateway", message)
+}
+
+/// A raw `503 Service Unavailable` response, sent back for requests arriving
+/// while the tunnel is shutting down
+pub fn service_unavailable(message: &str) -> Vec<u8> {
+    plain_response("503 Service Unavailable", message)
+}
+
+fn plain_response(status: &str, message: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        message.len(),
+        message
+    )
+    .into_bytes()
+}
+
+pub fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_chunks() {
+        let (body, trailers) = decode_chunked(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n").unwrap();
+        assert_eq!(body, b"hello world");
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn decodes_chunks_with_trailers() {
+        let (body, trailers) =
+            decode_chunked(b"3\r\nabc\r\n0\r\ngrpc-status: 0\r\nConnection: close\r\n\r\n").unwrap();
+        assert_eq!(body, b"abc");
+        // Hop-by-hop fields aren't passed on
+        assert_eq!(trailers, vec![("grpc-status".to_string(), "0".to_string())]);
+    }
+
+    #[test]
+    fn accepts_body_ending_after_last_chunk() {
+        let (body, trailers) = decode_chunked(b"3\r\nabc\r\n0\r\n").unwrap();
+        assert_eq!(body, b"abc");
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_chunk() {
+        assert!(decode_chunked(b"a\r\nabc").is_none());
+        assert!(decode_chunked(b"zz\r\nabc\r\n0\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn parses_trailers() {
+        assert_eq!(parse_trailers(b"\r\n"), Some(Vec::new()));
+        assert_eq!(parse_trailers(b""), Some(Vec::new()));
+        assert_eq!(
+            parse_trailers(b"x-checksum: abc \r\n\r\n"),
+            Some(vec![("x-checksum".to_string(), "abc".to_string())])
+        );
+        // The final blank line may be missing
+        assert_eq!(
+            parse_trailers(b"x-checksum: abc\r\n"),
+            Some(vec![("x-checksum".to_string(), "abc".to_string())])
+        );
+        assert_eq!(parse_trailers(b"not a header\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn summarizes_request_without_query() {
+        let request = b"GET /callback?code=secret HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert_eq!(request_summary(request).as_deref(), Some("GET /callback"));
+        assert_eq!(request_summary(b"GET /partial"), None);
+    }
+
+    #[test]
+    fn reads_status_code() {
+        assert_eq!(status_code(b"HTTP/1.1 404 Not Found\r\n\r\n"), Some(404));
+        assert_eq!(status_code(b"garbage\r\n"), None);
+    }
+}
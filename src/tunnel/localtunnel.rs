@@ -1,42 +1,51 @@
 use anyhow::{Context, Result};
 use tokio::process::Command;
-use tokio::io::{BufReader, AsyncBufReadExt};
+use tokio::io::BufReader;
 use std::process::Stdio;
 use regex::Regex;
 
 use crate::app::App;
-use super::TunnelResult;
+use super::{detect_provider_error, url_timeout, LossyLines, Provider, TunnelError, TunnelResult, URL_DETECTION_TIMEOUT};
 
-pub async fn start_tunnel(port: u16, subdomain: Option<String>, app: &mut App) -> Result<TunnelResult> {
+pub async fn start_tunnel(
+    port: u16,
+    subdomain: Option<String>,
+    extra_args: Vec<String>,
+    app: &mut App,
+) -> Result<TunnelResult> {
     app.add_log("Starting localtunnel...");
     
     // Check if lt is installed
-    let status = Command::new("which")
-        .arg("lt")
-        .status()
-        .await
-        .context("Failed to check if localtunnel is installed")?;
-    
-    if !status.success() {
-        app.add_log("Error: localtunnel not found. Please install it first.");
+    if !Provider::Localtunnel.is_installed() {
+        app.add_error("Error: localtunnel not found. Please install it first.");
         app.add_log("Installation instructions: npm install -g localtunnel");
-        return Err(anyhow::anyhow!("localtunnel not installed"));
+        return Err(TunnelError::ProviderNotInstalled { provider: Provider::Localtunnel.name() }.into());
     }
     
     // Prepare the command
     let mut cmd = Command::new("lt");
     cmd.args(["--port", &port.to_string()]);
+    if app.local_https {
+        // Local dev certificates are usually self-signed
+        cmd.args(["--local-https", "--allow-invalid-cert"]);
+    }
     
     // Add custom subdomain if provided
-    if let Some(subdomain) = subdomain {
-        cmd.args(["--subdomain", &subdomain]);
+    if let Some(subdomain) = &subdomain {
+        cmd.args(["--subdomain", subdomain]);
     }
     
+    // Pass through any user supplied flags
+    cmd.args(&extra_args);
+    
     // stdout and stderr will be captured so we can parse the URL
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
     
     app.add_log(&format!("Running command: lt --port {}", port));
+    if !extra_args.is_empty() {
+        app.add_log(&format!("Extra localtunnel arguments: {}", extra_args.join(" ")));
+    }
     
     // Start the process
     let mut child = cmd.spawn().context("Failed to start localtunnel process")?;
@@ -46,8 +55,8 @@ pub async fn start_tunnel(port: u16, subdomain: Option<String>, app: &mut App) -
     let stderr = child.stderr.take().expect("Failed to capture stderr");
     
     // Create readers
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+    let mut stdout_reader = LossyLines::new(BufReader::new(stdout));
+    let mut stderr_reader = LossyLines::new(BufReader::new(stderr));
     
     // Regex to extract the URL
     let url_regex = Regex::new(r"https://[^/\s]+").unwrap();
@@ -55,32 +64,44 @@ pub async fn start_tunnel(port: u16, subdomain: Option<String>, app: &mut App) -
     // This will store the tunnel URL once we find it
     let mut tunnel_url = String::new();
     
-    // Process stdout and stderr lines to find the URL
-    let stdout_future = async {
-        while let Some(line) = stdout_reader.next_line().await.unwrap_or(None) {
-            app.add_log(&format!("localtunnel: {}", line));
-            
-            // Try to find the tunnel URL
-            if tunnel_url.is_empty() {
+    // Give up if the provider never reports a URL
+    let deadline = tokio::time::sleep(URL_DETECTION_TIMEOUT);
+    tokio::pin!(deadline);
+    
+    // Process stdout and stderr lines until we find the URL or stdout closes
+    loop {
+        tokio::select! {
+            _ = &mut deadline => {
+                return Err(url_timeout(Provider::Localtunnel, &mut child, app).await);
+            }
+            line = stdout_reader.next_line() => {
+                let Some(line) = line.unwrap_or(None) else { break };
+                app.add_log(&format!("localtunnel: {}", line));
+                
+                if let Some(error) = detect_provider_error(Provider::Localtunnel, &line) {
+                    app.add_error(&format!("Error: {}", error));
+                    let _ = child.kill().await;
+                    return Err(error.into());
+                }
+                
+                // Try to find the tunnel URL
                 if let Some(url_match) = url_regex.find(&line) {
                     tunnel_url = url_match.as_str().to_string();
                     app.add_log(&format!("Tunnel URL found: {}", tunnel_url));
                     break;
                 }
             }
+            line = stderr_reader.next_line() => {
+                let Some(line) = line.unwrap_or(None) else { break };
+                app.add_error(&format!("localtunnel error: {}", line));
+                
+                if let Some(error) = detect_provider_error(Provider::Localtunnel, &line) {
+                    app.add_error(&format!("Error: {}", error));
+                    let _ = child.kill().await;
+                    return Err(error.into());
+                }
+            }
         }
-    };
-    
-    let stderr_future = async {
-        while let Some(line) = stderr_reader.next_line().await.unwrap_or(None) {
-            app.add_log(&format!("localtunnel error: {}", line));
-        }
-    };
-    
-    // Run both futures concurrently
-    tokio::select! {
-        _ = stdout_future => {},
-        _ = stderr_future => {},
     }
     
     // If we couldn't find a URL, use a default format
@@ -97,6 +118,8 @@ pub async fn start_tunnel(port: u16, subdomain: Option<String>, app: &mut App) -
     
     Ok(TunnelResult {
         url: tunnel_url,
-        process: child,
+        process: Some(child),
+        task: None,
+        drain: None,
     })
 }
\ No newline at end of file
@@ -1,7 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+pub mod cache;
 pub mod client;
+pub mod cloudflared;
+pub mod dashboard;
+pub mod error;
+pub mod http;
+pub mod localtunnel;
+pub mod ngrok;
+pub mod proxy;
+pub mod sequence;
 pub mod server;
+pub mod tls;
+
+pub use error::TunnelError;
+
+use crate::app::App;
+
+/// Which tunnel implementation exposes the local port
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// rshare's own tunnel server
+    #[default]
+    Builtin,
+    Ngrok,
+    Cloudflared,
+    Localtunnel,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 4] = [
+        Provider::Builtin,
+        Provider::Ngrok,
+        Provider::Cloudflared,
+        Provider::Localtunnel,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Builtin => "builtin",
+            Provider::Ngrok => "ngrok",
+            Provider::Cloudflared => "cloudflared",
+            Provider::Localtunnel => "localtunnel",
+        }
+    }
+
+    /// The executable an external provider needs, if any
+    pub fn binary(&self) -> Option<&'static str> {
+        match self {
+            Provider::Builtin => None,
+            Provider::Ngrok => Some("ngrok"),
+            Provider::Cloudflared => Some("cloudflared"),
+            Provider::Localtunnel => Some("lt"),
+        }
+    }
+
+    pub fn install_hint(&self) -> &'static str {
+        match self {
+            Provider::Builtin => "",
+            Provider::Ngrok => "https://ngrok.com/download",
+            Provider::Cloudflared => "https://developers.cloudflare.com/cloudflare-one/connections/connect-apps/install-and-setup/installation",
+            Provider::Localtunnel => "npm install -g localtunnel",
+        }
+    }
+
+    /// Whether the provider's binary can be found on the PATH
+    pub fn is_installed(&self) -> bool {
+        self.binary().is_none_or(is_on_path)
+    }
+}
+
+/// Whether an executable called `binary` is in one of the PATH directories,
+/// also trying a `.exe` suffix on Windows
+pub fn is_on_path(binary: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&path).any(|dir| {
+        let candidate = dir.join(binary);
+        candidate.is_file() || (cfg!(windows) && candidate.with_extension("exe").is_file())
+    })
+}
+
+/// How long to wait for an external provider to report its public URL
+pub const URL_DETECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Gives up on an external provider that didn't report its URL within
+/// `URL_DETECTION_TIMEOUT`, stopping its process so it doesn't linger
+pub async fn url_timeout(provider: Provider, child: &mut tokio::process::Child, app: &mut App) -> anyhow::Error {
+    app.add_error(&format!(
+        "Error: {} did not report a tunnel URL within {} seconds",
+        provider.name(),
+        URL_DETECTION_TIMEOUT.as_secs()
+    ));
+    let _ = child.kill().await;
+    TunnelError::UrlTimeout {
+        provider: provider.name(),
+    }
+    .into()
+}
+
+/// Output fragments from provider binaries that mean the tunnel can't come
+/// up, paired with the explanation shown to the user and whether it is an
+/// authentication problem
+const PROVIDER_ERRORS: &[(&str, &str, bool)] = &[
+    ("err_ngrok_4018", "ngrok requires a verified account and authtoken (run `ngrok config add-authtoken <token>`)", true),
+    ("err_ngrok_105", "ngrok rejected the authtoken, check it is valid", true),
+    ("err_ngrok_108", "ngrok account limit reached: only one simultaneous session is allowed", false),
+    ("authentication failed", "Authentication with the provider failed", true),
+    ("account limit", "Provider account limit reached", false),
+    ("quota", "Provider quota exceeded", false),
+    ("cannot determine default origin certificate path", "cloudflared is not logged in (run `cloudflared tunnel login`)", true),
+    ("429 too many requests", "Provider is rate limiting new tunnels, try again later", false),
+    ("subdomain is not available", "The requested subdomain is already taken", false),
+];
+
+/// Splits provider output into lines, replacing invalid UTF-8 rather than
+/// failing on it so binary noise doesn't swallow the lines around it
+pub struct LossyLines<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> LossyLines<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Returns the next line without its line ending, or `None` at EOF.
+    ///
+    /// Cancel safe: a partially read line is kept and finished on the next call.
+    pub async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let read = self.reader.read_until(b'\n', &mut self.buffer).await?;
+        if read == 0 && self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        let line = String::from_utf8_lossy(&self.buffer)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        self.buffer.clear();
+        Ok(Some(line))
+    }
+}
+
+/// Returns a descriptive error if a line of provider output reports a known failure
+pub fn detect_provider_error(provider: Provider, line: &str) -> Option<TunnelError> {
+    let line = line.to_lowercase();
+    PROVIDER_ERRORS
+        .iter()
+        .find(|(pattern, _, _)| line.contains(pattern))
+        .map(|(_, message, auth)| {
+            if *auth {
+                TunnelError::AuthFailed {
+                    provider: provider.name(),
+                    message,
+                }
+            } else {
+                TunnelError::ProviderFailed {
+                    provider: provider.name(),
+                    message,
+                }
+            }
+        })
+}
 
 pub struct TunnelResult {
     pub url: String,
-    pub process: tokio::process::Child,
+    /// Provider subprocess, if the tunnel runs out of process
+    pub process: Option<tokio::process::Child>,
+    /// Background forwarding task, if the tunnel runs in process
+    pub task: Option<tokio::task::JoinHandle<()>>,
+    /// Asks the in-process tunnel to stop taking new requests and end once
+    /// the in-flight ones finish
+    pub drain: Option<tokio::sync::watch::Sender<bool>>,
+}
+
+/// Events reported by a running tunnel back to the app
+#[derive(Debug)]
+pub enum TunnelEvent {
+    /// A line for the log pane
+    Log(String),
+    /// A line for the log pane reporting a failure
+    Error(String),
+    /// The connection to the server dropped
+    Disconnected { reason: String },
+    /// The client is trying to re-establish the connection
+    Reconnecting,
+    /// Bytes the server will still forward this session
+    Quota { remaining: u64 },
+    /// A forwarded request finished, with the bytes sent each way and how
+    /// long the local service took
+    RequestCompleted {
+        request_bytes: usize,
+        response_bytes: usize,
+        duration: Duration,
+        /// Id the server tagged the request with, shared with its logs
+        trace: Option<String>,
+        /// Method and path of the request, without the query string
+        request_line: Option<String>,
+        /// Status the local service answered with
+        status: Option<u16>,
+    },
+    /// The start of a finished request and its response, for the capture store
+    Captured {
+        trace: Option<String>,
+        request: Vec<u8>,
+        response: Vec<u8>,
+    },
+    /// Requests currently being forwarded to the local service, and those
+    /// waiting for a slot under the concurrency cap
+    InFlight { active: usize, queued: usize },
+    /// The tunnel ended for good and won't reconnect
+    Ended { reason: String },
 }
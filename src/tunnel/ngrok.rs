@@ -1,26 +1,64 @@
 use anyhow::{Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use serde::Deserialize;
 use tokio::process::Command;
-use tokio::io::{BufReader, AsyncBufReadExt};
+use tokio::io::BufReader;
 use std::process::Stdio;
-use regex::Regex;
+use std::time::Duration;
 
 use crate::app::App;
-use super::TunnelResult;
+use super::{detect_provider_error, url_timeout, LossyLines, Provider, TunnelError, TunnelResult, URL_DETECTION_TIMEOUT};
 
-pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> Result<TunnelResult> {
+/// ngrok's local agent API, which lists the active tunnels as JSON
+const NGROK_API_URL: &str = "http://127.0.0.1:4040/api/tunnels";
+
+#[derive(Deserialize)]
+struct TunnelList {
+    tunnels: Vec<ApiTunnel>,
+}
+
+#[derive(Deserialize)]
+struct ApiTunnel {
+    public_url: String,
+    config: ApiTunnelConfig,
+}
+
+#[derive(Deserialize)]
+struct ApiTunnelConfig {
+    addr: String,
+}
+
+/// Asks the ngrok agent for the public URL of the tunnel forwarding to `port`
+async fn query_tunnel_url(port: u16) -> Result<Option<String>> {
+    let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+    let response = client.get(NGROK_API_URL.parse()?).await?;
+    let body = response.into_body().collect().await?.to_bytes();
+    let list: TunnelList = serde_json::from_slice(&body)?;
+
+    let port_suffix = format!(":{}", port);
+    Ok(list
+        .tunnels
+        .into_iter()
+        .filter(|t| t.config.addr.ends_with(&port_suffix) || t.config.addr == port.to_string())
+        .find(|t| t.public_url.starts_with("https://"))
+        .map(|t| t.public_url))
+}
+
+pub async fn start_tunnel(
+    port: u16,
+    domain: Option<String>,
+    extra_args: Vec<String>,
+    app: &mut App,
+) -> Result<TunnelResult> {
     app.add_log("Starting ngrok tunnel...");
     
     // Check if ngrok is installed
-    let status = Command::new("which")
-        .arg("ngrok")
-        .status()
-        .await
-        .context("Failed to check if ngrok is installed")?;
-    
-    if !status.success() {
-        app.add_log("Error: ngrok not found. Please install it first.");
+    if !Provider::Ngrok.is_installed() {
+        app.add_error("Error: ngrok not found. Please install it first.");
         app.add_log("Installation instructions: https://ngrok.com/download");
-        return Err(anyhow::anyhow!("ngrok not installed"));
+        return Err(TunnelError::ProviderNotInstalled { provider: Provider::Ngrok.name() }.into());
     }
     
     // Prepare the command
@@ -28,18 +66,29 @@ pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> R
     cmd.arg("http");
     
     // Add custom domain if provided
-    if let Some(domain) = domain {
-        cmd.args(["--domain", &domain]);
+    if let Some(domain) = &domain {
+        cmd.args(["--domain", domain]);
     }
     
-    // Add the port
-    cmd.arg(port.to_string());
+    // Pass through any user supplied flags (e.g. --region, --authtoken)
+    cmd.args(&extra_args);
+    
+    // Add the port, or the full upstream URL for an HTTPS local service
+    let upstream = if app.local_https {
+        format!("https://localhost:{}", port)
+    } else {
+        port.to_string()
+    };
+    cmd.arg(&upstream);
     
     // stdout and stderr will be captured so we can parse the URL
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
     
-    app.add_log(&format!("Running command: ngrok http {}", port));
+    app.add_log(&format!("Running command: ngrok http {}", upstream));
+    if !extra_args.is_empty() {
+        app.add_log(&format!("Extra ngrok arguments: {}", extra_args.join(" ")));
+    }
     
     // Start the process
     let mut child = cmd.spawn().context("Failed to start ngrok process")?;
@@ -49,41 +98,53 @@ pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> R
     let stderr = child.stderr.take().expect("Failed to capture stderr");
     
     // Create readers
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+    let mut stdout_reader = LossyLines::new(BufReader::new(stdout));
+    let mut stderr_reader = LossyLines::new(BufReader::new(stderr));
     
-    // Regex to extract the URL
-    let url_regex = Regex::new(r"https://[^/\s]+").unwrap();
+    // Poll the agent API until the tunnel shows up
+    let mut api_poll = tokio::time::interval(Duration::from_millis(500));
     
     // This will store the tunnel URL once we find it
     let mut tunnel_url = String::new();
     
-    // Process stdout and stderr lines to find the URL
-    let stdout_future = async {
-        while let Some(line) = stdout_reader.next_line().await.unwrap_or(None) {
-            app.add_log(&format!("ngrok: {}", line));
-            
-            // Try to find the tunnel URL
-            if tunnel_url.is_empty() {
-                if let Some(url_match) = url_regex.find(&line) {
-                    tunnel_url = url_match.as_str().to_string();
+    // Give up if the provider never reports a URL
+    let deadline = tokio::time::sleep(URL_DETECTION_TIMEOUT);
+    tokio::pin!(deadline);
+    
+    // Log stdout and stderr until the API reports the URL or stdout closes
+    loop {
+        tokio::select! {
+            _ = &mut deadline => {
+                return Err(url_timeout(Provider::Ngrok, &mut child, app).await);
+            }
+            _ = api_poll.tick() => {
+                if let Ok(Some(url)) = query_tunnel_url(port).await {
+                    tunnel_url = url;
                     app.add_log(&format!("Tunnel URL found: {}", tunnel_url));
                     break;
                 }
             }
+            line = stdout_reader.next_line() => {
+                let Some(line) = line.unwrap_or(None) else { break };
+                app.add_log(&format!("ngrok: {}", line));
+                
+                if let Some(error) = detect_provider_error(Provider::Ngrok, &line) {
+                    app.add_error(&format!("Error: {}", error));
+                    let _ = child.kill().await;
+                    return Err(error.into());
+                }
+            }
+            line = stderr_reader.next_line() => {
+                let Some(line) = line.unwrap_or(None) else { break };
+                app.add_error(&format!("ngrok error: {}", line));
+                
+                if let Some(error) = detect_provider_error(Provider::Ngrok, &line) {
+                    app.add_error(&format!("Error: {}", error));
+                    let _ = child.kill().await;
+                    return Err(error.into());
+                }
+            }
         }
-    };
-    
-    let stderr_future = async {
-        while let Some(line) = stderr_reader.next_line().await.unwrap_or(None) {
-            app.add_log(&format!("ngrok error: {}", line));
-        }
-    };
-    
-    // Run both futures concurrently
-    tokio::select! {
-        _ = stdout_future => {},
-        _ = stderr_future => {},
     }
     
     // If we couldn't find a URL, use a default format
@@ -100,6 +161,8 @@ pub async fn start_tunnel(port: u16, domain: Option<String>, app: &mut App) -> R
     
     Ok(TunnelResult {
         url: tunnel_url,
-        process: child,
+        process: Some(child),
+        task: None,
+        drain: None,
     })
-}
\ No newline at end of file
+}
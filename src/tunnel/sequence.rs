@@ -0,0 +1,51 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+/// Frames held back waiting for an earlier one before the connection is
+/// given up on
+const MAX_PENDING_FRAMES: usize = 64;
+
+/// Puts one connection's `Data` frames back in the order they were sent,
+/// using the sequence number each carries
+#[derive(Default)]
+pub struct Reorder {
+    /// Sequence number of the next frame to deliver
+    next: u64,
+    /// Frames that arrived ahead of `next`
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Reorder {
+    /// Takes a frame and returns the bytes that can now be delivered in
+    /// order, if any. Fails on a repeated sequence number or when too many
+    /// frames are waiting on a missing one.
+    pub fn push(&mut self, seq: u64, data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if seq < self.next || self.pending.contains_key(&seq) {
+            bail!("frame {} arrived twice", seq);
+        }
+        if seq > self.next {
+            if self.pending.len() == MAX_PENDING_FRAMES {
+                bail!(
+                    "{} frames are waiting on frame {}, which never arrived",
+                    MAX_PENDING_FRAMES,
+                    self.next
+                );
+            }
+            self.pending.insert(seq, data);
+            return Ok(None);
+        }
+
+        let mut ready = data;
+        self.next += 1;
+        while let Some(data) = self.pending.remove(&self.next) {
+            ready.extend_from_slice(&data);
+            self.next += 1;
+        }
+        Ok(Some(ready))
+    }
+
+    /// Frames still waiting on an earlier one
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
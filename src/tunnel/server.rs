@@ -1,46 +1,288 @@
 use anyhow::{Context, Result};
+use base64::Engine;
+use regex::RegexSet;
 use futures_util::{SinkExt, StreamExt};
-use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
 use hyper::{
-    body::{Bytes, Incoming},
-    server::conn::http1,
+    body::{Bytes, Frame, Incoming},
     service::service_fn,
-    Request, Response, StatusCode,
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Method, Request, Response, StatusCode,
 };
-use hyper_util::rt::TokioIo;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use serde::Serialize;
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{
+            Callback, ErrorResponse, Request as WsRequest, Response as WsResponse,
+        },
+        protocol::{frame::coding::CloseCode, CloseFrame, Message},
+    },
+    WebSocketStream,
+};
 
-use crate::tunnel::client::TunnelMessage;
+use crate::config::{CorsConfig, ResponseCacheConfig, TlsConfig};
+use crate::tunnel::client::{
+    preview_frame, strip_scheme, Framing, TunnelMessage, ADMIN_LOGS_PATH, DATA_CHANNEL_PATH,
+    REGISTER_PATH, TRACE_HEADER,
+};
+use crate::tunnel::cache::{self, ResponseCache};
+use crate::tunnel::dashboard::{self, ClientSummary};
+use crate::tunnel::sequence::Reorder;
+use crate::tunnel::{http, tls, TunnelError};
 
 type Clients = Arc<Mutex<HashMap<String, ClientInfo>>>;
 
+/// Runtime options for the tunnel server
+#[derive(Debug, Default)]
+pub struct ServerOptions {
+    /// Suppress informational output, only printing errors
+    pub quiet: bool,
+    /// Bind to an OS-assigned port when a requested port is already in use
+    pub fallback_port: bool,
+    /// HTML served for requests that don't match any tunnel
+    pub landing_page: Option<String>,
+    /// URL unmatched requests are redirected to
+    pub landing_redirect: Option<String>,
+    /// Turn away new registrations, e.g. while draining before a restart
+    pub maintenance: bool,
+    /// Maximum number of connected clients
+    pub max_clients: Option<usize>,
+    /// Seconds rejected clients are told to wait before retrying
+    pub retry_after: Option<u64>,
+    /// Domains reserved for specific client tokens
+    pub reservations: HashMap<String, String>,
+    /// Reject clients without a reserved domain rather than falling back to
+    /// a subdomain named after their client id
+    pub require_reservation: bool,
+    /// Only forward GET and HEAD requests
+    pub read_only: bool,
+    /// Request paths refused with 403 before reaching the client
+    pub block_paths: Option<RegexSet>,
+    /// Health check paths answered with 200 by the server itself
+    pub probe_paths: Vec<String>,
+    /// CORS headers added to forwarded responses
+    pub cors: Option<CorsConfig>,
+    /// Answer with a description of each request instead of forwarding it
+    pub expose_headers: bool,
+    /// Artificial delay added to every response, for testing timeouts
+    pub inject_delay: Option<Duration>,
+    /// Bytes a client may forward per session before it is disconnected
+    pub traffic_cap: Option<u64>,
+    /// Request bodies above this many bytes are buffered on disk rather than in memory
+    pub spool_threshold: Option<u64>,
+    /// Bearer token for the `/admin/` endpoints, which are disabled without one
+    pub admin_token: Option<String>,
+    /// Token `CONNECT` requests must carry, which are refused without one
+    pub connect_token: Option<String>,
+    /// Headers added to every forwarded request, with `{version}` replaced by rshare's version
+    pub request_headers: HashMap<String, String>,
+    /// Mutual TLS required of clients on the WebSocket listener
+    pub tls: Option<TlsConfig>,
+    /// Hold idempotent requests interrupted by a client disconnect and replay
+    /// them once it reconnects, within the response timeout
+    pub retry_on_reconnect: bool,
+    /// Reuse responses to identical GET requests for a short while
+    pub response_cache: Option<ResponseCacheConfig>,
+    /// Guess a missing or generic Content-Type from the request path's extension
+    pub fix_mime_types: bool,
+    /// Show a live table of connected clients instead of printing log lines
+    pub dashboard: bool,
+    /// Messages queued for each client's WebSocket before senders have to wait
+    pub client_queue_size: Option<usize>,
+    /// Clients that go this long without a `KeepAlive` are disconnected
+    pub heartbeat_timeout: Option<Duration>,
+    /// Most headers a public request may carry
+    pub max_header_count: Option<usize>,
+    /// Most bytes a public request's header names and values may add up to
+    pub max_header_bytes: Option<usize>,
+}
+
+/// Prints an informational line unless the server is running quietly. It is
+/// streamed to `/admin/logs` subscribers either way.
+macro_rules! info {
+    ($options:expr, $($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        publish_log(&line);
+        if !$options.quiet && !DASHBOARD_ACTIVE.load(Ordering::Relaxed) {
+            println!("{}", line);
+        }
+    }};
+}
+
+/// Prints an error line, which is also streamed to `/admin/logs` subscribers
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        publish_log(&line);
+        if !DASHBOARD_ACTIVE.load(Ordering::Relaxed) {
+            eprintln!("{}", line);
+        }
+    }};
+}
+
+/// Set while the dashboard owns the terminal, which then shows log lines itself
+static DASHBOARD_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Log lines held for `/admin/logs` subscribers that fall behind
+const LOG_STREAM_CAPACITY: usize = 256;
+
+/// Every line the server logs, fanned out to `/admin/logs` subscribers
+static LOG_STREAM: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+
+fn log_stream() -> &'static broadcast::Sender<String> {
+    LOG_STREAM.get_or_init(|| broadcast::channel(LOG_STREAM_CAPACITY).0)
+}
+
+/// Timestamps a log line and hands it to any `/admin/logs` subscribers
+fn publish_log(line: &str) {
+    let stream = log_stream();
+    if stream.receiver_count() > 0 {
+        let _ = stream.send(format!("{} {}", chrono::Utc::now().to_rfc3339(), line));
+    }
+}
+
+/// Open public connections forwarded to a client, keyed by connection id,
+/// each receiving the response bytes the client sends back
+type Streams = Arc<Mutex<HashMap<u64, mpsc::Sender<Vec<u8>>>>>;
+
+/// How long to wait for the local service to respond through the tunnel
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Request bodies are forwarded to the client in pieces of at most this size
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How often a request held for a dropped client checks whether it has reconnected
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Messages queued for a client's WebSocket when no size is configured
+const DEFAULT_CLIENT_QUEUE_SIZE: usize = 100;
+
+/// Headers allowed on a public request when no limit is configured
+const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+
+/// Total header bytes allowed on a public request when no limit is configured
+const DEFAULT_MAX_HEADER_BYTES: usize = 32 * 1024;
+
+/// How long a lazily connected client's data channel may sit without traffic
+/// or open connections before it is closed
+const DATA_CHANNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a lazily connected client gets to open its data channel once asked
+const DATA_CHANNEL_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Source of ids distinguishing concurrent connections on one tunnel
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone)]
 struct ClientInfo {
-    domain: Option<String>,
+    /// The requested or reserved domain, or the one named after the client's label
+    domain: String,
+    /// Frames for the client's connections, written on its data channel if
+    /// it connects one lazily and on the registration socket otherwise
     sender: mpsc::Sender<Message>,
+    /// Where a lazily connected client's data channel is attached
+    data_channel: Option<DataChannel>,
+    streams: Streams,
+    /// Host header forwarded requests carry instead of the public host
+    host_header: Option<String>,
+    /// Path prefix the local app is mounted under
+    base_path: Option<String>,
+    /// Headers added to every response, replacing the local service's
+    response_headers: HashMap<String, String>,
+    /// How `Data` frames to and from this client are written
+    framing: Framing,
+    /// Bytes forwarded in either direction during this session
+    bytes_used: Arc<AtomicU64>,
+    /// Requests forwarded during this session
+    requests: Arc<AtomicU64>,
+    /// When this session registered
+    connected_at: Instant,
+    /// Cleared once this session's WebSocket has closed
+    connected: Arc<AtomicBool>,
+    /// Recent responses reused for identical GET requests, when enabled
+    cache: Option<Arc<Mutex<ResponseCache>>>,
+    /// The most recent requests forwarded to this client, oldest first
+    recent_requests: Arc<Mutex<VecDeque<RequestRecord>>>,
+}
+
+/// The session side of a lazily connected client's data channel
+#[derive(Clone)]
+struct DataChannel {
+    /// Checked against the token the client attaches the channel with
+    token: String,
+    /// Hands a newly attached channel to the session's relay
+    attach: mpsc::Sender<mpsc::Sender<Message>>,
+    /// Frames the client sends on the channel, read like those on the
+    /// registration socket
+    incoming: mpsc::Sender<Vec<u8>>,
 }
 
-pub async fn run(port: u16) -> Result<()> {
+/// Number of forwarded requests remembered per client for `/admin/requests`
+const REQUEST_LOG_SIZE: usize = 100;
+
+/// A forwarded request as reported by `/admin/requests`
+#[derive(Serialize, Clone)]
+struct RequestRecord {
+    method: String,
+    path: String,
+    status: u16,
+    timestamp: String,
+    trace: String,
+}
+
+/// Runs the server until it is stopped. On SIGHUP, `reload` is called for
+/// fresh options, which apply to requests and registrations from then on.
+pub async fn run(
+    port: u16,
+    options: ServerOptions,
+    reload: impl Fn() -> Result<ServerOptions> + Send + 'static,
+) -> Result<()> {
     // Create shared state
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let options = Arc::new(options);
+    let (options_tx, options_rx) = watch::channel(options.clone());
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(options_tx, reload));
+    #[cfg(not(unix))]
+    let _ = (options_tx, reload);
+
+    // Certificates are loaded up front so a bad path fails at startup
+    let tls_acceptor = options.tls.as_ref().map(tls::acceptor).transpose()?;
 
     // Start WebSocket server for tunneling
-    let ws_addr = format!("0.0.0.0:{}", port);
-    let ws_listener = TcpListener::bind(&ws_addr).await?;
-    println!("WebSocket server listening on {}", ws_addr);
+    let ws_listener = bind_listener(port, &options).await?;
+    info!(
+        options,
+        "WebSocket server listening on {}{}",
+        ws_listener.local_addr()?,
+        if tls_acceptor.is_some() { " (mutual TLS)" } else { "" }
+    );
 
     // Start HTTP server for handling public requests
-    let http_addr = format!("0.0.0.0:{}", port + 1);
-    let http_listener = TcpListener::bind(&http_addr).await?;
-    println!("HTTP server listening on {}", http_addr);
+    let http_listener = bind_listener(port + 1, &options).await?;
+    info!(options, "HTTP server listening on {}", http_listener.local_addr()?);
 
     // Clone the clients reference for the HTTP server
     let http_clients = clients.clone();
+    let http_options = options_rx.clone();
 
     // Spawn HTTP server task
     tokio::spawn(async move {
@@ -48,51 +290,488 @@ pub async fn run(port: u16) -> Result<()> {
             match http_listener.accept().await {
                 Ok((stream, _)) => {
                     let clients = http_clients.clone();
+                    let options = http_options.clone();
                     tokio::spawn(async move {
-                        if let Err(err) = handle_http_connection(stream, clients).await {
-                            eprintln!("Error in HTTP connection: {}", err);
+                        if let Err(err) = handle_http_connection(stream, clients, options).await {
+                            error!("Error in HTTP connection: {}", err);
                         }
                     });
                 }
                 Err(e) => {
-                    eprintln!("Failed to accept HTTP connection: {}", e);
+                    error!("Failed to accept HTTP connection: {}", e);
                 }
             }
         }
     });
 
-    // Handle WebSocket connections
+    if options.dashboard {
+        let (summaries_tx, summaries_rx) = watch::channel(Vec::new());
+        let logs = log_stream().subscribe();
+        DASHBOARD_ACTIVE.store(true, Ordering::Relaxed);
+        tokio::spawn(publish_summaries(clients.clone(), summaries_tx));
+        tokio::spawn(accept_clients(ws_listener, clients, options_rx, tls_acceptor));
+        let result = dashboard::run(summaries_rx, logs).await;
+        DASHBOARD_ACTIVE.store(false, Ordering::Relaxed);
+        return result;
+    }
+
+    accept_clients(ws_listener, clients, options_rx, tls_acceptor).await;
+    Ok(())
+}
+
+/// Re-reads the config on every SIGHUP and swaps in the new options, keeping
+/// the current ones if the config is broken
+#[cfg(unix)]
+async fn reload_on_sighup(
+    options: watch::Sender<Arc<ServerOptions>>,
+    reload: impl Fn() -> Result<ServerOptions>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Can't listen for SIGHUP, config reloading is off: {}", e);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        let current = options.borrow().clone();
+        let mut reloaded = match reload() {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!("Failed to reload config, keeping the current one: {:#}", e);
+                continue;
+            }
+        };
+
+        // The listener's certificates were loaded at startup
+        if reloaded.tls != current.tls {
+            error!("Ignoring changed tls settings, they only take effect after a restart");
+            reloaded.tls = current.tls.clone();
+        }
+
+        let changed = changed_options(&current, &reloaded);
+        if changed.is_empty() {
+            info!(reloaded, "Reloaded config, nothing changed");
+        } else {
+            info!(reloaded, "Reloaded config, changed: {}", changed.join(", "));
+        }
+        options.send_replace(Arc::new(reloaded));
+    }
+}
+
+/// Names of the reloadable options that differ between `old` and `new`
+#[cfg(unix)]
+fn changed_options(old: &ServerOptions, new: &ServerOptions) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    macro_rules! compare {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if old.$field != new.$field {
+                    changed.push(stringify!($field));
+                }
+            )*
+        };
+    }
+    compare!(
+        landing_page,
+        landing_redirect,
+        maintenance,
+        max_clients,
+        retry_after,
+        reservations,
+        require_reservation,
+    );
+    // A compiled RegexSet can't be compared, the patterns it was built from can
+    let old_patterns = old.block_paths.as_ref().map(RegexSet::patterns);
+    if old_patterns != new.block_paths.as_ref().map(RegexSet::patterns) {
+        changed.push("block_paths");
+    }
+    compare!(
+        probe_paths,
+        cors,
+        traffic_cap,
+        spool_threshold,
+        admin_token,
+        connect_token,
+        request_headers,
+        retry_on_reconnect,
+        response_cache,
+        fix_mime_types,
+        client_queue_size,
+        heartbeat_timeout,
+        max_header_count,
+        max_header_bytes,
+    );
+    changed
+}
+
+/// Accepts tunnel clients on the WebSocket listener for as long as the server runs
+async fn accept_clients(
+    ws_listener: TcpListener,
+    clients: Clients,
+    options: watch::Receiver<Arc<ServerOptions>>,
+    tls_acceptor: Option<TlsAcceptor>,
+) {
     loop {
         if let Ok((stream, addr)) = ws_listener.accept().await {
             let clients = clients.clone();
+            // Each connection keeps the options it was accepted under
+            let options = options.borrow().clone();
+            let tls_acceptor = tls_acceptor.clone();
             tokio::spawn(async move {
-                if let Err(err) = handle_ws_connection(stream, addr, clients).await {
-                    eprintln!("Error in WebSocket connection: {}", err);
+                let result = match tls_acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => handle_ws_connection(stream, addr, clients, options.clone()).await,
+                        Err(e) => Err(anyhow::anyhow!("TLS handshake with {} failed: {}", addr, e)),
+                    },
+                    None => handle_ws_connection(stream, addr, clients, options.clone()).await,
+                };
+                if let Err(err) = result {
+                    error!("Error in WebSocket connection: {}", err);
                 }
             });
         }
     }
 }
 
-async fn handle_ws_connection(stream: TcpStream, addr: SocketAddr, clients: Clients) -> Result<()> {
-    println!("New WebSocket connection: {}", addr);
+/// Refreshes the dashboard's view of connected clients on every tick
+async fn publish_summaries(clients: Clients, summaries: watch::Sender<Vec<ClientSummary>>) {
+    loop {
+        let snapshot = clients
+            .lock()
+            .await
+            .iter()
+            .map(|(id, info)| ClientSummary {
+                client_id: id.clone(),
+                domain: info.domain.clone(),
+                connected_for: info.connected_at.elapsed(),
+                requests: info.requests.load(Ordering::Relaxed),
+                bytes: info.bytes_used.load(Ordering::Relaxed),
+            })
+            .collect();
+        if summaries.send(snapshot).is_err() {
+            return;
+        }
+        tokio::time::sleep(dashboard::TICK_RATE).await;
+    }
+}
+
+/// Binds a listener on all interfaces, optionally falling back to an
+/// OS-assigned port if the requested one is taken
+async fn bind_listener(port: u16, options: &ServerOptions) -> Result<TcpListener> {
+    let addr = format!("0.0.0.0:{}", port);
+
+    match TcpListener::bind(&addr).await {
+        Ok(listener) => Ok(listener),
+        Err(e) if options.fallback_port && e.kind() == std::io::ErrorKind::AddrInUse => {
+            info!(options, "Port {} is already in use, picking a free port instead", port);
+            TcpListener::bind("0.0.0.0:0")
+                .await
+                .context("Failed to bind fallback port")
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            Err(TunnelError::PortInUse { port }.into())
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to bind {}", addr)),
+    }
+}
+
+/// Applies domain reservations: a reserved domain can only be claimed with
+/// its owner's token, and an owner who doesn't ask for a domain gets theirs. With
+/// `require_reservation`, only reserved domains are handed out
+fn reserved_domain(
+    options: &ServerOptions,
+    token: Option<&str>,
+    requested: Option<String>,
+) -> std::result::Result<Option<String>, String> {
+    if options.require_reservation {
+        let reserved = token.and_then(|token| options.reservations.get(token));
+        return match (reserved, requested) {
+            (None, _) => Err("This server only accepts clients with a reserved domain".to_string()),
+            (Some(reserved), Some(domain)) if normalize_domain(reserved) != domain => {
+                Err(format!("{} is not reserved for this token", domain))
+            }
+            (Some(reserved), _) => Ok(Some(normalize_domain(reserved))),
+        };
+    }
+
+    let Some(domain) = requested else {
+        return Ok(token
+            .and_then(|token| options.reservations.get(token))
+            .map(|reserved| normalize_domain(reserved)));
+    };
+
+    // A name inside or around someone else's reservation is theirs too
+    let taken = options.reservations.iter().find(|(owner, reserved)| {
+        Some(owner.as_str()) != token && domains_overlap(&normalize_domain(reserved), &domain)
+    });
+
+    match taken {
+        Some((_, reserved)) if normalize_domain(reserved) == domain => Err(format!("{} is reserved", domain)),
+        Some((_, reserved)) => Err(format!("{} overlaps {}, which is reserved", domain, reserved)),
+        None => Ok(Some(domain)),
+    }
+}
+
+/// A domain as it is compared against request hosts: lowercase, without a
+/// trailing dot
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether two normalized domains are the same or one is a subdomain of the other
+fn domains_overlap(a: &str, b: &str) -> bool {
+    a == b || a.ends_with(&format!(".{}", b)) || b.ends_with(&format!(".{}", a))
+}
+
+/// The normalized domain a `Host` header names, without its port
+fn request_domain(host: &str) -> String {
+    let name = match host.rsplit_once(':') {
+        // Leave the colons inside an unbracketed IPv6 address alone
+        Some((name, port))
+            if !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (!name.contains(':') || name.ends_with(']')) =>
+        {
+            name
+        }
+        _ => host,
+    };
+    normalize_domain(name)
+}
+
+/// Returns why a registration should be turned away, if it should be
+async fn rejection_reason(
+    clients: &Clients,
+    client_id: &str,
+    domain: &str,
+    options: &ServerOptions,
+) -> Option<String> {
+    if options.maintenance {
+        return Some("Server is under maintenance".to_string());
+    }
 
-    let ws_stream = accept_async(stream)
+    // The same client re-registering after a reconnect keeps its domain
+    let in_use = clients
+        .lock()
+        .await
+        .iter()
+        .any(|(id, info)| id != client_id && info.domain == domain);
+    if in_use {
+        return Some(format!("{} is already in use by another client", domain));
+    }
+
+    if let Some(max_clients) = options.max_clients {
+        let clients_lock = clients.lock().await;
+        // A client re-registering after a reconnect doesn't take a new slot
+        if !clients_lock.contains_key(client_id) && clients_lock.len() >= max_clients {
+            return Some("Server is full".to_string());
+        }
+    }
+
+    None
+}
+
+/// Sends every log line to an admin subscriber until it disconnects
+async fn stream_logs<S>(ws_stream: WebSocketStream<S>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut lines = log_stream().subscribe();
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Ok(line) => ws_sender.send(Message::Text(line)).await?,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    ws_sender
+                        .send(Message::Text(format!("... {} lines skipped", skipped)))
+                        .await?
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = ws_receiver.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(Message::Ping(payload))) => ws_sender.send(Message::Pong(payload)).await?,
+                _ => {}
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Notes the path a WebSocket upgrade was requested on, turning away log
+/// subscribers without the admin token before the upgrade completes
+struct UpgradeCheck<'a> {
+    path: &'a mut String,
+    admin_token: Option<&'a str>,
+}
+
+impl Callback for UpgradeCheck<'_> {
+    fn on_request(
+        self,
+        req: &WsRequest,
+        response: WsResponse,
+    ) -> Result<WsResponse, ErrorResponse> {
+        *self.path = req.uri().path().to_string();
+        if self.path != ADMIN_LOGS_PATH {
+            return Ok(response);
+        }
+
+        let token = req
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let status = match (self.admin_token, token) {
+            (None, _) => StatusCode::NOT_FOUND,
+            (Some(expected), Some(token)) if token_matches(token, expected) => return Ok(response),
+            (Some(_), _) => StatusCode::UNAUTHORIZED,
+        };
+        let mut error = ErrorResponse::new(Some(status.to_string()));
+        *error.status_mut() = status;
+        Err(error)
+    }
+}
+
+async fn handle_ws_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    clients: Clients,
+    options: Arc<ServerOptions>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    info!(options, "New WebSocket connection: {}", addr);
+
+    let mut path = String::new();
+    let check = UpgradeCheck {
+        path: &mut path,
+        admin_token: options.admin_token.as_deref(),
+    };
+    let ws_stream = accept_hdr_async(stream, check)
         .await
         .context("Failed to accept WebSocket connection")?;
+
+    if path == ADMIN_LOGS_PATH {
+        info!(options, "Streaming logs to {}", addr);
+        return stream_logs(ws_stream).await;
+    }
+    if path == DATA_CHANNEL_PATH {
+        return serve_data_channel(ws_stream, addr, clients, options).await;
+    }
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
+    // Tell clients on the wrong endpoint why, rather than waiting for a
+    // registration that will never make sense
+    if path != REGISTER_PATH {
+        error!("Rejected WebSocket connection from {} on unknown path {}", addr, path);
+        let close = CloseFrame {
+            code: CloseCode::Policy,
+            reason: format!("unknown tunnel endpoint {}, expected {}", path, REGISTER_PATH).into(),
+        };
+        let _ = ws_sender.send(Message::Close(Some(close))).await;
+        return Ok(());
+    }
+
     // Handle the first message to determine the type of connection
     if let Some(Ok(Message::Binary(data))) = ws_receiver.next().await {
-        match serde_json::from_slice::<TunnelMessage>(&data) {
-            Ok(TunnelMessage::Register { client_id, domain }) => {
-                println!(
-                    "Client registered: {} with domain: {:?}",
+        match TunnelMessage::decode(&data) {
+            Ok(TunnelMessage::Register {
+                client_id: label,
+                instance_id,
+                domain,
+                token,
+                host_header,
+                base_path,
+                response_headers,
+                binary_data,
+                sequenced,
+                lazy_connect,
+            }) => {
+                // Sessions are keyed on the instance, so two clients that
+                // happen to pick the same label can't take over each other's
+                // tunnel; the label only names the subdomain
+                let client_id = instance_id.unwrap_or_else(|| label.clone());
+
+                // Tolerate a URL pasted in place of a domain
+                let domain = domain.map(|domain| normalize_domain(strip_scheme(&domain)));
+                let domain = match reserved_domain(&options, token.as_deref(), domain) {
+                    Ok(domain) => domain.unwrap_or_else(|| {
+                        normalize_domain(&format!("{}.public.dev.peril.lol", label))
+                    }),
+                    Err(reason) => {
+                        info!(options, "Rejected client {}: {}", client_id, reason);
+                        let response = TunnelMessage::Rejected {
+                            reason,
+                            retry_after: None,
+                        };
+                        ws_sender
+                            .send(Message::Binary(serde_json::to_vec(&response)?))
+                            .await?;
+                        ws_sender.close().await?;
+                        return Ok(());
+                    }
+                };
+
+                if let Some(reason) = rejection_reason(&clients, &client_id, &domain, &options).await {
+                    info!(options, "Rejected client {}: {}", client_id, reason);
+                    let response = TunnelMessage::Rejected {
+                        reason,
+                        retry_after: options.retry_after,
+                    };
+                    ws_sender
+                        .send(Message::Binary(serde_json::to_vec(&response)?))
+                        .await?;
+                    ws_sender.close().await?;
+                    return Ok(());
+                }
+
+                info!(
+                    options,
+                    "Client registered: {} with domain: {}",
                     client_id, &domain
                 );
 
                 // Create a channel for this client
-                let (sender, mut receiver) = mpsc::channel::<Message>(100);
+                // Once it is full, requests wait for the client to catch up
+                // rather than piling up in memory
+                let queue_size = options.client_queue_size.unwrap_or(DEFAULT_CLIENT_QUEUE_SIZE);
+                let (sender, mut receiver) = mpsc::channel::<Message>(queue_size);
+                let streams: Streams = Arc::new(Mutex::new(HashMap::new()));
+                let bytes_used = Arc::new(AtomicU64::new(0));
+                let connected = Arc::new(AtomicBool::new(true));
+
+                // A lazily connected client's frames go through a relay that
+                // asks it to open a data channel when there's something to send
+                let (incoming, mut data_frames) = mpsc::channel::<Vec<u8>>(queue_size);
+                let (data_sender, data_channel) = if lazy_connect {
+                    let (data_sender, frames) = mpsc::channel::<Message>(queue_size);
+                    let (attach, attached) = mpsc::channel(1);
+                    tokio::spawn(relay_data(
+                        frames,
+                        attached,
+                        sender.clone(),
+                        streams.clone(),
+                        client_id.clone(),
+                        options.clone(),
+                    ));
+                    let data_channel = DataChannel {
+                        token: uuid::Uuid::new_v4().simple().to_string(),
+                        attach,
+                        incoming,
+                    };
+                    (data_sender, Some(data_channel))
+                } else {
+                    (sender.clone(), None)
+                };
+                let data_token = data_channel.as_ref().map(|channel| channel.token.clone());
 
                 // Store client info with cloned domain
                 {
@@ -101,72 +780,220 @@ async fn handle_ws_connection(stream: TcpStream, addr: SocketAddr, clients: Clie
                         client_id.clone(),
                         ClientInfo {
                             domain: domain.clone(),
-                            sender: sender.clone(),
+                            sender: data_sender.clone(),
+                            data_channel,
+                            streams: streams.clone(),
+                            host_header,
+                            base_path,
+                            response_headers,
+                            framing: Framing {
+                                binary: binary_data,
+                                sequenced,
+                            },
+                            bytes_used: bytes_used.clone(),
+                            requests: Arc::new(AtomicU64::new(0)),
+                            connected_at: Instant::now(),
+                            connected: connected.clone(),
+                            cache: options.response_cache.as_ref().map(|config| {
+                                Arc::new(Mutex::new(ResponseCache::new(
+                                    config.max_entries,
+                                    Duration::from_secs(config.ttl_secs),
+                                )))
+                            }),
+                            recent_requests: Arc::new(Mutex::new(VecDeque::new())),
                         },
                     );
                 }
 
-                // Generate and send the tunnel URL
-                let domain_part = if let Some(domain_val) = &domain {
-                    domain_val.clone()
-                } else {
-                    format!("{}.public.dev.peril.lol", client_id)
-                };
-
-                let tunnel_url = format!("https://{}", domain_part);
+                let tunnel_url = format!("https://{}", domain);
+                // Agree to binary and numbered data frames if the client can
+                // read them
                 let response = TunnelMessage::Registered {
                     url: tunnel_url.clone(),
+                    binary_data,
+                    sequenced,
+                    data_token,
                 };
 
                 ws_sender
                     .send(Message::Binary(serde_json::to_vec(&response)?))
                     .await?;
-                println!("Sent registration confirmation: {}", tunnel_url);
+                info!(options, "Sent registration confirmation: {}", tunnel_url);
 
-                // Create a separate task for handling messages to the client
-                let sender_for_ws = sender.clone();
+                if let Some(cap) = options.traffic_cap {
+                    let quota = TunnelMessage::Quota { remaining: cap };
+                    ws_sender
+                        .send(Message::Binary(serde_json::to_vec(&quota)?))
+                        .await?;
+                }
 
-                // Now handle the data forwarding for this client
+                // Everything sent to the client goes through the channel, so
+                // requests and control replies share the one socket
                 tokio::spawn(async move {
                     // Forward messages from receiver to WebSocket
                     while let Some(msg) = receiver.recv().await {
-                        if sender_for_ws.send(msg).await.is_err() {
+                        if ws_sender.send(msg).await.is_err() {
                             break;
                         }
                     }
                 });
 
+                // Responses that arrived ahead of an earlier frame, per connection
+                let mut reorder: HashMap<u64, Reorder> = HashMap::new();
+
                 // Handle incoming WebSocket messages
-                while let Some(Ok(msg)) = ws_receiver.next().await {
+                let mut last_heartbeat = Instant::now();
+                loop {
+                    // Frames from the data channel are handled as if they had
+                    // arrived on this socket
+                    let next_frame = async {
+                        tokio::select! {
+                            next = ws_receiver.next() => next,
+                            Some(data) = data_frames.recv() => Some(Ok(Message::Binary(data))),
+                        }
+                    };
+                    let next = match options.heartbeat_timeout {
+                        Some(limit) => {
+                            match tokio::time::timeout_at(last_heartbeat + limit, next_frame).await {
+                                Ok(next) => next,
+                                Err(_) => {
+                                    info!(options, "Client {} missed its heartbeat, disconnecting", client_id);
+                                    let _ = sender.send(Message::Close(None)).await;
+                                    break;
+                                }
+                            }
+                        }
+                        None => next_frame.await,
+                    };
+                    let Some(Ok(msg)) = next else { break };
                     match msg {
                         Message::Binary(data) => {
-                            if let Ok(tunnel_msg) = serde_json::from_slice::<TunnelMessage>(&data) {
-                                match tunnel_msg {
-                                    TunnelMessage::Data { data: response_data } => {
-                                        // This would be handled by the HTTP connection handler
-                                        println!(
-                                            "Received data response from client: {} bytes",
-                                            response_data.len()
-                                        );
+                            let tunnel_msg = match TunnelMessage::decode(&data) {
+                                Ok(tunnel_msg) => tunnel_msg,
+                                Err(e) => {
+                                    // Tell the client rather than silently dropping the frame
+                                    error!(
+                                        "Malformed message from client {}: {} ({})",
+                                        client_id,
+                                        e,
+                                        preview_frame(&data)
+                                    );
+                                    let error = TunnelMessage::Error {
+                                        message: format!("Malformed message: {}", e),
+                                    };
+                                    let _ = sender
+                                        .send(Message::Binary(serde_json::to_vec(&error)?))
+                                        .await;
+                                    continue;
+                                }
+                            };
+                            match tunnel_msg {
+                                TunnelMessage::Data {
+                                    connection_id,
+                                    seq,
+                                    data: response_data,
+                                } => {
+                                    info!(
+                                        options,
+                                        "Received data response from client: {} bytes",
+                                        response_data.len()
+                                    );
+
+                                    let used = bytes_used.fetch_add(
+                                        response_data.len() as u64,
+                                        Ordering::Relaxed,
+                                    ) + response_data.len() as u64;
+                                    if options.traffic_cap.is_some_and(|cap| used > cap) {
+                                        info!(options, "Client {} exceeded its traffic cap", client_id);
+                                        let deregister = TunnelMessage::Deregister {
+                                            reason: "quota exceeded".to_string(),
+                                        };
+                                        let _ = sender
+                                            .send(Message::Binary(serde_json::to_vec(&deregister)?))
+                                            .await;
+                                        let _ = sender.send(Message::Close(None)).await;
+                                        break;
+                                    }
+
+                                    // Hold back frames that overtook an earlier one
+                                    let response_data = if sequenced {
+                                        let pushed = reorder.entry(connection_id).or_default().push(seq, response_data);
+                                        match pushed {
+                                            Ok(Some(data)) => data,
+                                            Ok(None) => continue,
+                                            Err(e) => {
+                                                error!(
+                                                    "Dropping connection {} from client {}: {}",
+                                                    connection_id, client_id, e
+                                                );
+                                                reorder.remove(&connection_id);
+                                                streams.lock().await.remove(&connection_id);
+                                                let close = TunnelMessage::Close { connection_id };
+                                                let _ = sender
+                                                    .send(Message::Binary(serde_json::to_vec(&close)?))
+                                                    .await;
+                                                continue;
+                                            }
+                                        }
+                                    } else {
+                                        response_data
+                                    };
+
+                                    // Hand the bytes to the request waiting on this connection
+                                    let stream = streams.lock().await.get(&connection_id).cloned();
+                                    if let Some(stream) = stream {
+                                        let _ = stream.send(response_data).await;
                                     }
-                                    TunnelMessage::KeepAlive => {
-                                        // Send keep-alive response
-                                        if let Err(e) = ws_sender
-                                            .send(Message::Binary(serde_json::to_vec(
-                                                &TunnelMessage::KeepAlive,
-                                            )?))
-                                            .await
-                                        {
-                                            eprintln!("Error sending keep-alive: {}", e);
-                                            break;
+                                }
+                                TunnelMessage::Close { connection_id } => {
+                                    // Dropping the sender completes the response
+                                    streams.lock().await.remove(&connection_id);
+                                    if let Some(state) = reorder.remove(&connection_id) {
+                                        if state.pending() > 0 {
+                                            error!(
+                                                "Connection {} from client {} closed with {} frame(s) still out of order",
+                                                connection_id,
+                                                client_id,
+                                                state.pending()
+                                            );
                                         }
                                     }
-                                    _ => {
-                                        println!("Ignoring unexpected message type");
+
+                                    if let Some(cap) = options.traffic_cap {
+                                        let used = bytes_used.load(Ordering::Relaxed);
+                                        let quota = TunnelMessage::Quota {
+                                            remaining: cap.saturating_sub(used),
+                                        };
+                                        let _ = sender
+                                            .send(Message::Binary(serde_json::to_vec(&quota)?))
+                                            .await;
                                     }
                                 }
+                                TunnelMessage::KeepAlive => {
+                                    last_heartbeat = Instant::now();
+                                    // Send keep-alive response
+                                    if let Err(e) = sender
+                                        .send(Message::Binary(serde_json::to_vec(
+                                            &TunnelMessage::KeepAlive,
+                                        )?))
+                                        .await
+                                    {
+                                        error!("Error sending keep-alive: {}", e);
+                                        break;
+                                    }
+                                }
+                                _ => {
+                                    info!(options, "Ignoring unexpected message type");
+                                }
                             }
                         }
+                        // Answer protocol pings from the client or a proxy in
+                        // between, which may drop the connection otherwise
+                        Message::Ping(payload)
+                            if sender.send(Message::Pong(payload.clone())).await.is_err() =>
+                        {
+                            break;
+                        }
                         Message::Close(_) => {
                             break;
                         }
@@ -174,13 +1001,37 @@ async fn handle_ws_connection(stream: TcpStream, addr: SocketAddr, clients: Clie
                     }
                 }
 
-                // Client disconnected, remove from active clients
+                // Fail any requests still waiting on this connection
+                connected.store(false, Ordering::Relaxed);
+                streams.lock().await.clear();
+
+                // Client disconnected, remove from active clients unless it
+                // has already re-registered on a new connection
                 let mut clients_lock = clients.lock().await;
-                clients_lock.remove(&client_id);
-                println!("Client disconnected: {}", client_id);
+                if clients_lock
+                    .get(&client_id)
+                    .is_some_and(|info| info.sender.same_channel(&data_sender))
+                {
+                    clients_lock.remove(&client_id);
+                }
+                info!(options, "Client disconnected: {}", client_id);
+            }
+            Err(e) => {
+                error!(
+                    "Malformed registration from {}: {} ({})",
+                    addr,
+                    e,
+                    preview_frame(&data)
+                );
+                let error = TunnelMessage::Error {
+                    message: format!("Malformed registration: {}", e),
+                };
+                ws_sender
+                    .send(Message::Binary(serde_json::to_vec(&error)?))
+                    .await?;
             }
             _ => {
-                println!("Received unexpected message type on initial connection");
+                info!(options, "Received unexpected message type on initial connection");
             }
         }
     }
@@ -188,21 +1039,194 @@ async fn handle_ws_connection(stream: TcpStream, addr: SocketAddr, clients: Clie
     Ok(())
 }
 
-async fn handle_http_connection(tcp_stream: TcpStream, clients: Clients) -> Result<()> {
+/// Serves a lazily connected client's data channel: the first frame names the
+/// session it belongs to, after which frames from the client go to that
+/// session's handler and frames for it come from the session's relay
+async fn serve_data_channel<S>(
+    ws_stream: WebSocketStream<S>,
+    addr: SocketAddr,
+    clients: Clients,
+    options: Arc<ServerOptions>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let Some(Ok(Message::Binary(data))) = ws_receiver.next().await else {
+        return Ok(());
+    };
+    let attached = match TunnelMessage::decode(&data) {
+        Ok(TunnelMessage::AttachDataChannel { instance_id: client_id, token }) => {
+            let channel = clients
+                .lock()
+                .await
+                .get(&client_id)
+                .and_then(|info| info.data_channel.clone());
+            channel
+                .filter(|channel| token_matches(&token, &channel.token))
+                .map(|channel| (client_id, channel))
+        }
+        _ => None,
+    };
+    let Some((client_id, channel)) = attached else {
+        error!("Rejected data channel from {}: no matching session", addr);
+        let close = CloseFrame {
+            code: CloseCode::Policy,
+            reason: "no matching session".into(),
+        };
+        let _ = ws_sender.send(Message::Close(Some(close))).await;
+        return Ok(());
+    };
+
+    let (link, mut frames) = mpsc::channel::<Message>(DEFAULT_CLIENT_QUEUE_SIZE);
+    if channel.attach.send(link).await.is_err() {
+        return Ok(());
+    }
+    info!(options, "Data channel opened for client {}", client_id);
+
+    loop {
+        tokio::select! {
+            frame = frames.recv() => {
+                // The relay lets go of the channel once it has gone idle
+                let Some(frame) = frame else {
+                    let _ = ws_sender.send(Message::Close(None)).await;
+                    break;
+                };
+                if ws_sender.send(frame).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws_receiver.next() => {
+                let open = match msg {
+                    // The session has ended if nothing reads its frames
+                    Some(Ok(Message::Binary(data))) => channel.incoming.send(data).await.is_ok(),
+                    Some(Ok(Message::Ping(payload))) => {
+                        ws_sender.send(Message::Pong(payload)).await.is_ok()
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => false,
+                    _ => true,
+                };
+                if !open {
+                    break;
+                }
+            }
+        }
+    }
+
+    info!(options, "Data channel closed for client {}", client_id);
+    Ok(())
+}
+
+/// Writes `frames` for a lazily connected client to its data channel. When no
+/// channel is open it asks the client for one over `control` and waits for it
+/// to attach; once the channel has been idle with no open connections for
+/// `DATA_CHANNEL_IDLE_TIMEOUT`, it is closed again.
+async fn relay_data(
+    mut frames: mpsc::Receiver<Message>,
+    mut attached: mpsc::Receiver<mpsc::Sender<Message>>,
+    control: mpsc::Sender<Message>,
+    streams: Streams,
+    client_id: String,
+    options: Arc<ServerOptions>,
+) {
+    let mut link: Option<mpsc::Sender<Message>> = None;
+    loop {
+        let mut frame = match tokio::time::timeout(DATA_CHANNEL_IDLE_TIMEOUT, frames.recv()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(_) => {
+                if link.is_some() && streams.lock().await.is_empty() {
+                    info!(options, "Closing idle data channel for client {}", client_id);
+                    link = None;
+                }
+                continue;
+            }
+        };
+
+        loop {
+            let current = match &link {
+                Some(current) => current.clone(),
+                None => {
+                    let opened = match attached.try_recv() {
+                        Ok(opened) => Some(opened),
+                        Err(_) => {
+                            let Ok(request) = serde_json::to_vec(&TunnelMessage::OpenDataChannel) else {
+                                return;
+                            };
+                            if control.send(Message::Binary(request)).await.is_err() {
+                                return;
+                            }
+                            match tokio::time::timeout(DATA_CHANNEL_OPEN_TIMEOUT, attached.recv()).await {
+                                Ok(Some(opened)) => Some(opened),
+                                Ok(None) => return,
+                                Err(_) => None,
+                            }
+                        }
+                    };
+                    let Some(opened) = opened else {
+                        // Dropping the stream answers the request waiting on
+                        // this frame with 502 now, rather than at its timeout
+                        error!("Client {} didn't open its data channel in time", client_id);
+                        if let Ok(TunnelMessage::Data { connection_id, .. }) =
+                            TunnelMessage::decode(&frame.into_data())
+                        {
+                            streams.lock().await.remove(&connection_id);
+                        }
+                        break;
+                    };
+                    link = Some(opened.clone());
+                    opened
+                }
+            };
+
+            // A channel that has closed since hands the frame back to try on
+            // a new one
+            match current.send(frame).await {
+                Ok(()) => break,
+                Err(mpsc::error::SendError(unsent)) => {
+                    frame = unsent;
+                    link = None;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_http_connection(
+    tcp_stream: TcpStream,
+    clients: Clients,
+    options: watch::Receiver<Arc<ServerOptions>>,
+) -> Result<()> {
     let io = TokioIo::new(tcp_stream);
 
-    // Process the HTTP request
-    if let Err(err) = http1::Builder::new()
-        .serve_connection(
+    // Serve HTTP/1.1 or HTTP/2, whichever the client speaks. Pipelined
+    // HTTP/1.1 requests are handled one at a time, each over its own tunnel
+    // connection id, so their responses go back in the order they were
+    // asked for; flushing them together saves a write per response.
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    // HTTP/1.1 requests with too many headers are refused while parsing,
+    // before any of them are stored; `handle_request` checks the rest
+    let max_header_count = options
+        .borrow()
+        .max_header_count
+        .unwrap_or(DEFAULT_MAX_HEADER_COUNT);
+    builder
+        .http1()
+        .pipeline_flush(true)
+        .max_headers(max_header_count);
+    if let Err(err) = builder
+        .serve_connection_with_upgrades(
             io,
             service_fn(move |req| {
                 let clients = clients.clone();
+                // Picked up per request, so a reload applies to open connections
+                let options = options.borrow().clone();
                 async move {
-                    let result = handle_request(req, clients).await;
+                    let result = handle_request(req, clients, options).await;
                     match result {
                         Ok(response) => Ok::<_, anyhow::Error>(response),
                         Err(e) => {
-                            eprintln!("Error handling request: {}", e);
+                            error!("Error handling request: {}", e);
 
                             // Return 500 Internal Server Error
                             let body = json!({
@@ -222,104 +1246,1125 @@ async fn handle_http_connection(tcp_stream: TcpStream, clients: Clients) -> Resu
         )
         .await
     {
-        eprintln!("Error serving HTTP connection: {}", err);
+        error!("Error serving HTTP connection: {}", err);
     }
 
     Ok(())
 }
 
 async fn handle_request(
-    req: Request<Incoming>,
+    mut req: Request<Incoming>,
     clients: Clients,
+    options: Arc<ServerOptions>,
 ) -> Result<Response<BoxBody<Bytes, anyhow::Error>>> {
+    if let Some(problem) = header_limit_exceeded(req.headers(), &options) {
+        error!("Rejecting request: {}", problem);
+        return Ok(Response::builder()
+            .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+            .body(full_body(format!("Request header fields too large: {}", problem)))
+            .unwrap());
+    }
+
     // Extract the host from the request
     let host = match req.headers().get("host") {
-        Some(h) => h.to_str().unwrap_or("").to_string(),
-        None => return Ok(not_found_response()),
+        Some(h) => match h.to_str() {
+            Ok(host) => host.to_string(),
+            Err(_) => {
+                error!("Rejecting request with malformed host header: {:?}", h.as_bytes());
+                return Ok(bad_request_response("Malformed Host header"));
+            }
+        },
+        // HTTP/2 carries the host in the :authority pseudo-header instead
+        None => match req.uri().authority() {
+            Some(authority) => authority.to_string(),
+            None => return Ok(unmatched_response(&options)),
+        },
     };
     
     // Get the original URI and convert to string
     let uri = req.uri().to_string();
     
-    // Extract the base domain without path components for routing
-    let base_domain = host.split('/').next().unwrap_or(&host).to_string();
+    // Extract the base domain without path components or port for routing
+    let base_domain = request_domain(host.split('/').next().unwrap_or(&host));
 
     // Find the client based on the host
-    let (client_id, sender) = {
+    let matched_client = {
         let clients_lock = clients.lock().await;
 
-        // First try to match by domain
-        let mut matched_client = None;
+        clients_lock
+            .iter()
+            .find(|(_, info)| info.domain == base_domain)
+            .map(|(id, info)| (id.clone(), info.clone()))
+    };
 
-        for (id, info) in clients_lock.iter() {
-            if let Some(domain) = &info.domain {
-                if base_domain.starts_with(domain) {
-                    matched_client = Some((id.clone(), info.sender.clone()));
-                    break;
-                }
-            }
+    let (client_id, client) = match matched_client {
+        Some(client) => client,
+        None if req.uri().path() == "/admin" || req.uri().path().starts_with("/admin/") => {
+            return Ok(admin_response(&req, &clients, &options).await);
         }
+        None => return Ok(unmatched_response(&options)),
+    };
+
+    // Health checks are answered here, without logging or counting them
+    if options.probe_paths.iter().any(|probe| probe == req.uri().path()) {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(full_body("OK".to_string()))
+            .unwrap());
+    }
+
+    // Tag the request so the server's and client's logs can be matched up,
+    // replacing any id the caller made up
+    let trace = trace_id();
+    req.headers_mut()
+        .insert(TRACE_HEADER, HeaderValue::from_str(&trace).unwrap());
+
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+
+    // A raw stream to the local service rather than a request for it
+    if req.method() == Method::CONNECT {
+        let response = connect_response(req, &client_id, client.clone(), &options, &trace).await;
+        record_request(&client, method, uri, response.status(), trace).await;
+        return Ok(response);
+    }
+
+    let mut response = forward_to_client(req, &client_id, client.clone(), &clients, &host, &uri, &options).await?;
+    response
+        .headers_mut()
+        .insert(TRACE_HEADER, HeaderValue::from_str(&trace).unwrap());
+    record_request(&client, method, path, response.status(), trace).await;
+
+    Ok(response)
+}
+
+/// Describes how a request's headers go over the configured limits, if they do
+fn header_limit_exceeded(headers: &HeaderMap, options: &ServerOptions) -> Option<String> {
+    let max_count = options.max_header_count.unwrap_or(DEFAULT_MAX_HEADER_COUNT);
+    if headers.len() > max_count {
+        return Some(format!("{} headers, at most {} allowed", headers.len(), max_count));
+    }
+
+    // Counted as they would be written out: `name: value\r\n`
+    let max_bytes = options.max_header_bytes.unwrap_or(DEFAULT_MAX_HEADER_BYTES);
+    let bytes: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    if bytes > max_bytes {
+        return Some(format!("{} bytes of headers, at most {} allowed", bytes, max_bytes));
+    }
+    None
+}
+
+/// A short random id for one forwarded request
+fn trace_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+async fn record_request(
+    client: &ClientInfo,
+    method: String,
+    path: String,
+    status: StatusCode,
+    trace: String,
+) {
+    client.requests.fetch_add(1, Ordering::Relaxed);
+    let mut recent_requests = client.recent_requests.lock().await;
+    if recent_requests.len() == REQUEST_LOG_SIZE {
+        recent_requests.pop_front();
+    }
+    recent_requests.push_back(RequestRecord {
+        method,
+        path,
+        status: status.as_u16(),
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        trace,
+    });
+}
+
+/// Compares a token a caller presented with the configured one in constant
+/// time, so response timing doesn't reveal how much of it was right
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Browser dashboard served at `/admin`
+const ADMIN_PAGE: &str = include_str!("admin.html");
 
-        // If no domain match, try to match by client ID subdomain
-        if matched_client.is_none() {
+/// Serves the operator endpoints: `/admin` is a browser dashboard,
+/// `/admin/clients` lists connected clients and
+/// `/admin/requests?client_id=...` returns a client's recent requests
+async fn admin_response(
+    req: &Request<Incoming>,
+    clients: &Clients,
+    options: &ServerOptions,
+) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let Some(admin_token) = &options.admin_token else {
+        return unmatched_response(options);
+    };
+
+    // The page holds no data itself; its script asks for the token and
+    // polls `/admin/clients` with it
+    if matches!(req.uri().path(), "/admin" | "/admin/") {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .body(full_body(ADMIN_PAGE.to_string()))
+            .unwrap();
+    }
+
+    let authorized = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token_matches(token, admin_token));
+    if !authorized {
+        return json_response(StatusCode::UNAUTHORIZED, json!({ "error": "Unauthorized" }));
+    }
+
+    match req.uri().path() {
+        "/admin/clients" => {
+            let clients_lock = clients.lock().await;
+            let mut list = Vec::new();
             for (id, info) in clients_lock.iter() {
-                if base_domain.starts_with(&format!("{}.public.dev.peril.lol", id)) {
-                    matched_client = Some((id.clone(), info.sender.clone()));
-                    break;
+                list.push(json!({
+                    "client_id": id,
+                    "domain": info.domain,
+                    "bytes_used": info.bytes_used.load(Ordering::Relaxed),
+                    "requests": info.requests.load(Ordering::Relaxed),
+                    "connected_secs": info.connected_at.elapsed().as_secs(),
+                    "open_connections": info.streams.lock().await.len(),
+                }));
+            }
+            json_response(StatusCode::OK, json!(list))
+        }
+        "/admin/requests" => {
+            let client_id = req.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| *key == "client_id")
+                    .map(|(_, value)| value.to_string())
+            });
+            let Some(client_id) = client_id else {
+                return json_response(
+                    StatusCode::BAD_REQUEST,
+                    json!({ "error": "Missing client_id parameter" }),
+                );
+            };
+
+            let recent_requests = clients
+                .lock()
+                .await
+                .get(&client_id)
+                .map(|info| info.recent_requests.clone());
+            match recent_requests {
+                Some(recent_requests) => {
+                    let requests: Vec<RequestRecord> =
+                        recent_requests.lock().await.iter().cloned().collect();
+                    json_response(StatusCode::OK, json!(requests))
                 }
+                None => json_response(StatusCode::NOT_FOUND, json!({ "error": "Unknown client" })),
+            }
+        }
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "Not found" })),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(full_body(body.to_string()))
+        .unwrap()
+}
+
+/// Whether a request's response can be shared with everyone making the same
+/// request: a GET or HEAD that carries no credentials and asks for the whole resource
+fn is_shareable_request(req: &Request<Incoming>) -> bool {
+    matches!(*req.method(), Method::GET | Method::HEAD)
+        && !is_websocket_upgrade(req.headers())
+        && !["authorization", "cookie", "range"]
+            .iter()
+            .any(|name| req.headers().contains_key(*name))
+}
+
+/// Waits until the deadline for a dropped client to register again under the
+/// same id, returning its new session
+async fn await_reconnect(
+    clients: &Clients,
+    client_id: &str,
+    old_sender: &mpsc::Sender<Message>,
+    deadline: Instant,
+) -> Option<ClientInfo> {
+    while Instant::now() < deadline {
+        if let Some(info) = clients.lock().await.get(client_id) {
+            if !info.sender.same_channel(old_sender) {
+                return Some(info.clone());
             }
         }
+        tokio::time::sleep(RECONNECT_POLL_INTERVAL).await;
+    }
+    None
+}
+
+/// Forwards a request matched to `client` through its tunnel
+async fn forward_to_client(
+    mut req: Request<Incoming>,
+    client_id: &str,
+    client: ClientInfo,
+    clients: &Clients,
+    host: &str,
+    uri: &str,
+    options: &ServerOptions,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>> {
+    let origin = req
+        .headers()
+        .get("origin")
+        .and_then(|origin| origin.to_str().ok())
+        .map(str::to_string);
+
+    let request_path = req.uri().path().to_string();
+    let trace = req
+        .headers()
+        .get(TRACE_HEADER)
+        .and_then(|trace| trace.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
 
-        match matched_client {
-            Some(client) => client,
-            None => return Ok(not_found_response()),
+    if let Some(cors) = &options.cors {
+        let is_preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key("access-control-request-method");
+        if is_preflight {
+            return Ok(preflight_response(
+                cors,
+                origin.as_deref(),
+                req.headers().get("access-control-request-headers"),
+            ));
         }
+    }
+
+    if options.read_only && !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", "GET, HEAD")
+            .body(full_body("Method not allowed: this tunnel is read-only".to_string()))
+            .unwrap());
+    }
+
+    if options
+        .block_paths
+        .as_ref()
+        .is_some_and(|block_paths| block_paths.is_match(req.uri().path()))
+    {
+        info!(options, "[{}] Blocked request for {}", trace, req.uri().path());
+        return Ok(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(full_body("Forbidden".to_string()))
+            .unwrap());
+    }
+
+    // Serve repeated GETs from the cache while the stored response is fresh
+    let cache_key = client
+        .cache
+        .as_ref()
+        .filter(|_| is_shareable_request(&req))
+        .map(|_| format!("{} {}", req.method(), uri));
+    if let (Some(cache), Some(key)) = (&client.cache, &cache_key) {
+        let cached = cache.lock().await.get(key);
+        if let Some(parsed) = cached {
+            info!(options, "[{}] Serving cached response for {}", trace, uri);
+            let mut response = build_response(parsed);
+            if options.fix_mime_types {
+                correct_content_type(&request_path, response.headers_mut());
+            }
+            if let Some(cors) = &options.cors {
+                apply_cors_headers(cors, origin.as_deref(), response.headers_mut());
+            }
+            apply_response_headers(&client.response_headers, response.headers_mut());
+            return Ok(response);
+        }
+    }
+
+    info!(options, "[{}] {} {} -> client {}", trace, req.method(), uri, client_id);
+
+    let websocket = is_websocket_upgrade(req.headers());
+    let body = if websocket {
+        None
+    } else {
+        Some(read_request_body(req.body_mut(), options.spool_threshold).await?)
     };
 
-    println!("Forwarding request to client: {} with URI: {}", client_id, uri);
+    // Create a request structure that includes the full URI and method. The
+    // local service closes the connection after responding, which is how the
+    // client knows the response is complete.
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let path = match &client.base_path {
+        Some(base_path) => prefix_path(base_path, path),
+        None => path.to_string(),
+    };
+    let request_data = if websocket {
+        // Keep the connection open and let the local service answer the handshake
+        format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n{}: {}\r\n{}{}\r\n",
+            req.method(),
+            path,
+            client.host_header.as_deref().unwrap_or(host),
+            TRACE_HEADER,
+            trace,
+            websocket_handshake_headers(req.headers()),
+            tunnel_request_headers(options)
+        )
+    } else {
+        format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}: {}\r\n{}{}\r\n",
+            req.method(),
+            path,
+            client.host_header.as_deref().unwrap_or(host),
+            TRACE_HEADER,
+            trace,
+            body_headers(req.headers(), body.as_ref()),
+            tunnel_request_headers(options)
+        )
+    };
 
-    // Create a channel for the response
-    let (_tx, mut rx) = mpsc::channel::<Vec<u8>>(1);
+    let body_len = body.as_ref().map_or(0, RequestBody::len);
 
-    // Create a request structure that includes the full URI and method
-    let request_data = format!(
-        "{} {} HTTP/1.1\r\nHost: {}\r\n\r\n",
-        req.method(),
-        req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/"),
-        host
-    );
+    // Show what would have been forwarded instead of forwarding it
+    if options.expose_headers {
+        return Ok(echo_response(&req, &path, &request_data, body_len));
+    }
 
-    // Create a message to send to the client with the full request data
-    let tunnel_msg = TunnelMessage::Data { data: request_data.into_bytes() };
+    // Idempotent requests without a body can be replayed if the client drops
+    // and reconnects before answering
+    let retryable = options.retry_on_reconnect
+        && !websocket
+        && body_len == 0
+        && matches!(*req.method(), Method::GET | Method::HEAD);
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    let request_data = request_data.into_bytes();
+    let mut body = body;
+    let mut client = client;
 
-    // Send the request to the client
-    sender
-        .send(Message::Binary(serde_json::to_vec(&tunnel_msg)?))
-        .await?;
+    // The connection id doubles as the request id, kept across retries
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+
+    let response_data = loop {
+        client
+            .bytes_used
+            .fetch_add(request_data.len() as u64 + body_len, Ordering::Relaxed);
+
+        // Create a channel for the response
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(32);
+        client.streams.lock().await.insert(connection_id, tx);
+
+        // Create a message to send to the client with the full request data
+        let tunnel_msg = TunnelMessage::Data {
+            connection_id,
+            seq: 0,
+            data: request_data.clone(),
+        };
+
+        // Send the request to the client
+        if let Err(e) = client.sender
+            .send(Message::Binary(tunnel_msg.encode(client.framing)?))
+            .await
+        {
+            client.streams.lock().await.remove(&connection_id);
+            return Err(e.into());
+        }
+
+        // Sequence number of the next frame for this connection
+        let mut seq = 1;
+        if let Some(body) = body.take() {
+            if let Err(e) = send_request_body(&client, connection_id, &mut seq, body).await {
+                close_stream(&client, connection_id).await;
+                return Err(e);
+            }
+        }
+
+        if websocket {
+            return forward_websocket(req, connection_id, seq, client, rx).await;
+        }
+
+        // Collect the response until the client closes the connection
+        let collect_response = async {
+            // Simulate a slow local service; the delay counts against the timeout
+            if let Some(delay) = options.inject_delay {
+                info!(options, "Delaying response by {} ms", delay.as_millis());
+                tokio::time::sleep(delay).await;
+            }
+
+            let mut response = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                response.extend_from_slice(&chunk);
+            }
+            response
+        };
+
+        // Wait for the response until the deadline
+        match tokio::time::timeout_at(deadline, collect_response).await {
+            Ok(data) if !data.is_empty() => break data,
+            Ok(_) => {
+                if retryable && !client.connected.load(Ordering::Relaxed) {
+                    if let Some(reconnected) =
+                        await_reconnect(clients, client_id, &client.sender, deadline).await
+                    {
+                        info!(
+                            options,
+                            "[{}] Retrying request after client {} reconnected",
+                            trace, client_id
+                        );
+                        client = reconnected;
+                        continue;
+                    }
+                }
 
-    // Wait for the response with a timeout
-    let response_data =
-        match tokio::time::timeout(std::time::Duration::from_secs(30), rx.recv()).await {
-            Ok(Some(data)) => data,
-            Ok(None) => {
                 return Ok(Response::builder()
                     .status(StatusCode::BAD_GATEWAY)
                     .body(full_body("Client disconnected".to_string()))
                     .unwrap());
             }
             Err(_) => {
+                // Let the client release its side of the connection
+                client.streams.lock().await.remove(&connection_id);
+                let close = TunnelMessage::Close { connection_id };
+                let _ = client.sender
+                    .send(Message::Binary(serde_json::to_vec(&close)?))
+                    .await;
+
                 return Ok(Response::builder()
                     .status(StatusCode::GATEWAY_TIMEOUT)
                     .body(full_body("Request timed out".to_string()))
                     .unwrap());
             }
-        };
+        }
+    };
 
     // Parse and return the response
-    Ok(Response::builder()
+    let mut response = match http::parse_response(&response_data) {
+        Some(parsed) => {
+            if let (Some(cache), Some(key)) = (&client.cache, cache_key) {
+                if cache::is_cacheable(&parsed) {
+                    cache.lock().await.insert(key, parsed.clone());
+                }
+            }
+            build_response(parsed)
+        }
+        // Not something we understand, pass the bytes through untouched
+        None => Response::builder()
+            .status(StatusCode::OK)
+            .body(full_body(response_data))
+            .unwrap(),
+    };
+
+    if options.fix_mime_types {
+        correct_content_type(&request_path, response.headers_mut());
+    }
+    if let Some(cors) = &options.cors {
+        apply_cors_headers(cors, origin.as_deref(), response.headers_mut());
+    }
+    apply_response_headers(&client.response_headers, response.headers_mut());
+
+    Ok(response)
+}
+
+/// Sets the Content-Type from the path's extension when the local service
+/// left it out or fell back to `application/octet-stream`, which browsers
+/// won't treat as a stylesheet or script
+fn correct_content_type(path: &str, headers: &mut HeaderMap) {
+    if let Some(value) = headers.get("content-type") {
+        let essence = value.to_str().unwrap_or("").split(';').next().unwrap_or("");
+        if !essence.trim().eq_ignore_ascii_case("application/octet-stream") {
+            return;
+        }
+    }
+    if let Some(mime) = mime_type_for(path) {
+        headers.insert("content-type", HeaderValue::from_static(mime));
+    }
+}
+
+/// Content-Type for common static file extensions
+fn mime_type_for(path: &str) -> Option<&'static str> {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let mime = match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" | "cjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+/// Adds the client's configured headers, skipping any that aren't valid
+fn apply_response_headers(configured: &HashMap<String, String>, headers: &mut HeaderMap) {
+    for (name, value) in configured {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Whether the request asks to upgrade the connection to a WebSocket
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get("upgrade")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// The public client's handshake headers the local service needs, including
+/// the subprotocols it asked for, as raw header lines
+fn websocket_handshake_headers(headers: &HeaderMap) -> String {
+    let mut lines = String::new();
+    for (name, value) in headers {
+        let name = name.as_str();
+        if !name.starts_with("sec-websocket-") && name != "origin" {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            lines.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    lines
+}
+
+/// Waits for the local service to answer a WebSocket handshake and, once it
+/// accepts, pipes the upgraded connection through the tunnel both ways
+async fn forward_websocket(
+    req: Request<Incoming>,
+    connection_id: u64,
+    seq: u64,
+    client: ClientInfo,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>> {
+    let read_head = async {
+        let mut data = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            data.extend_from_slice(&chunk);
+            if http::head_len(&data).is_some() {
+                break;
+            }
+        }
+        data
+    };
+
+    let data = tokio::time::timeout(RESPONSE_TIMEOUT, read_head)
+        .await
+        .unwrap_or_default();
+    let parsed = http::head_len(&data)
+        .and_then(|head_len| http::parse_response(&data[..head_len]).map(|parsed| (head_len, parsed)));
+    let Some((head_len, parsed)) = parsed else {
+        close_stream(&client, connection_id).await;
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(full_body("Local service did not answer the WebSocket handshake".to_string()))
+            .unwrap());
+    };
+
+    // Turned down, e.g. a 404 or 403; pass the answer on and drop the connection
+    if parsed.status != StatusCode::SWITCHING_PROTOCOLS.as_u16() {
+        close_stream(&client, connection_id).await;
+        return Ok(build_response(http::ParsedResponse {
+            body: data[head_len..].to_vec(),
+            ..parsed
+        }));
+    }
+
+    // Relay the local service's choice of subprotocol and extensions
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade");
+    for (name, value) in &parsed.headers {
+        if name.to_lowercase().starts_with("sec-websocket-") {
+            response = response.header(name.as_str(), value.as_str());
+        }
+    }
+
+    // Frames the local service sent straight after its handshake
+    let leftover = data[head_len..].to_vec();
+    let upgrade = hyper::upgrade::on(req);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => pipe_upgraded(upgraded, leftover, connection_id, seq, &client, rx).await,
+            Err(e) => error!("WebSocket upgrade failed: {}", e),
+        }
+        close_stream(&client, connection_id).await;
+    });
+
+    Ok(response.body(full_body("")).unwrap())
+}
+
+/// Answers `CONNECT` by relaying raw bytes between the caller and the
+/// client's local service, the same way an upgraded WebSocket is relayed
+async fn connect_response(
+    req: Request<Incoming>,
+    client_id: &str,
+    client: ClientInfo,
+    options: &ServerOptions,
+    trace: &str,
+) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let Some(connect_token) = &options.connect_token else {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(full_body("CONNECT is not enabled on this server".to_string()))
+            .unwrap();
+    };
+    if options.read_only {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", "GET, HEAD")
+            .body(full_body("Method not allowed: this tunnel is read-only".to_string()))
+            .unwrap();
+    }
+    if !is_proxy_authorized(req.headers(), connect_token) {
+        return Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header("Proxy-Authenticate", "Basic realm=\"rshare\"")
+            .body(full_body("Proxy authentication required".to_string()))
+            .unwrap();
+    }
+
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(32);
+    client.streams.lock().await.insert(connection_id, tx);
+
+    // An empty first frame has the client connect to its local service
+    // straight away, for protocols where the service speaks first
+    let mut seq = 0;
+    if let Err(e) = send_data(&client, connection_id, &mut seq, Vec::new()).await {
+        client.streams.lock().await.remove(&connection_id);
+        error!("[{}] Failed to open a CONNECT stream to client {}: {}", trace, client_id, e);
+        return Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(full_body("Client disconnected".to_string()))
+            .unwrap();
+    }
+    info!(options, "[{}] Opened a CONNECT stream to client {}", trace, client_id);
+
+    let upgrade = hyper::upgrade::on(req);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => pipe_upgraded(upgraded, Vec::new(), connection_id, seq, &client, rx).await,
+            Err(e) => error!("CONNECT upgrade failed: {}", e),
+        }
+        close_stream(&client, connection_id).await;
+    });
+
+    Response::builder()
         .status(StatusCode::OK)
-        .body(full_body(response_data))
-        .unwrap())
+        .body(full_body(String::new()))
+        .unwrap()
+}
+
+/// Whether `Proxy-Authorization` carries `token`, either as a bearer token
+/// or as the password of basic credentials, which is what most tools send
+fn is_proxy_authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(value) = headers
+        .get("proxy-authorization")
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    if let Some(bearer) = value.strip_prefix("Bearer ") {
+        return token_matches(bearer, token);
+    }
+    value
+        .strip_prefix("Basic ")
+        .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .is_some_and(|credentials| {
+            credentials
+                .split_once(':')
+                .is_some_and(|(_, password)| token_matches(password, token))
+        })
+}
+
+/// Relays bytes both ways between an upgraded public connection and the
+/// client until either side closes
+async fn pipe_upgraded(
+    upgraded: hyper::upgrade::Upgraded,
+    leftover: Vec<u8>,
+    connection_id: u64,
+    mut seq: u64,
+    client: &ClientInfo,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+) {
+    let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+
+    // Local service to public client
+    let to_public = async {
+        if writer.write_all(&leftover).await.is_err() {
+            return;
+        }
+        while let Some(chunk) = rx.recv().await {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    // Public client to local service
+    let to_local = async {
+        let mut buffer = vec![0; 8192];
+        loop {
+            let n = match reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            client.bytes_used.fetch_add(n as u64, Ordering::Relaxed);
+
+            let message = TunnelMessage::Data {
+                connection_id,
+                seq,
+                data: buffer[..n].to_vec(),
+            };
+            seq += 1;
+            let Ok(bytes) = message.encode(client.framing) else {
+                break;
+            };
+            if client.sender.send(Message::Binary(bytes)).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    // Either side closing ends the connection
+    tokio::select! {
+        _ = to_public => {}
+        _ = to_local => {}
+    }
+}
+
+/// A request body read from the public client, held in memory or spooled to disk
+enum RequestBody {
+    Memory(Vec<u8>),
+    Spooled(SpoolFile),
+}
+
+impl RequestBody {
+    fn len(&self) -> u64 {
+        match self {
+            RequestBody::Memory(data) => data.len() as u64,
+            RequestBody::Spooled(spool) => spool.len,
+        }
+    }
+}
+
+/// A temporary file holding a large request body, removed once dropped
+/// whether or not forwarding succeeded
+struct SpoolFile {
+    path: PathBuf,
+    file: tokio::fs::File,
+    len: u64,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reads the whole request body, moving it to a temporary file once it grows
+/// past `spool_threshold` so large uploads don't have to fit in memory
+async fn read_request_body(body: &mut Incoming, spool_threshold: Option<u64>) -> Result<RequestBody> {
+    let mut buffer = Vec::new();
+    let mut spool: Option<SpoolFile> = None;
+
+    while let Some(frame) = body.frame().await {
+        // Request trailers aren't forwarded
+        let Ok(data) = frame.context("Failed to read request body")?.into_data() else {
+            continue;
+        };
+
+        if let Some(spool) = &mut spool {
+            spool.file.write_all(&data).await.context("Failed to spool request body")?;
+            spool.len += data.len() as u64;
+            continue;
+        }
+
+        buffer.extend_from_slice(&data);
+        if spool_threshold.is_some_and(|threshold| buffer.len() as u64 > threshold) {
+            let path = std::env::temp_dir().join(format!("rshare-upload-{}", uuid::Uuid::new_v4()));
+            // Bodies may carry credentials, so other users mustn't read them
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).read(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let file = options
+                .open(&path)
+                .await
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            let mut spooled = SpoolFile { path, file, len: 0 };
+            spooled.file.write_all(&buffer).await.context("Failed to spool request body")?;
+            spooled.len = buffer.len() as u64;
+            buffer = Vec::new();
+            spool = Some(spooled);
+        }
+    }
+
+    match spool {
+        Some(mut spool) => {
+            spool.file.flush().await?;
+            spool.file.rewind().await?;
+            Ok(RequestBody::Spooled(spool))
+        }
+        None => Ok(RequestBody::Memory(buffer)),
+    }
+}
+
+/// Describes a request and the head the client would have been sent for it,
+/// for `--expose-headers`
+fn echo_response(
+    req: &Request<Incoming>,
+    forwarded_path: &str,
+    forwarded_head: &str,
+    body_size: u64,
+) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let mut headers = serde_json::Map::new();
+    for name in req.headers().keys() {
+        let values: Vec<_> = req
+            .headers()
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .collect();
+        headers.insert(name.to_string(), json!(values));
+    }
+
+    json_response(
+        StatusCode::OK,
+        json!({
+            "method": req.method().as_str(),
+            "uri": req.uri().to_string(),
+            "version": format!("{:?}", req.version()),
+            "headers": headers,
+            "body_size": body_size,
+            "forwarded_path": forwarded_path,
+            "forwarded_head": forwarded_head,
+        }),
+    )
+}
+
+/// The operator's marker headers for tunneled requests, as raw header lines
+fn tunnel_request_headers(options: &ServerOptions) -> String {
+    let mut lines = String::new();
+    for (name, value) in &options.request_headers {
+        let value = value.replace("{version}", env!("CARGO_PKG_VERSION"));
+        // Keep a stray newline in the config from injecting extra headers
+        if name.contains(['\r', '\n', ':']) || value.contains(['\r', '\n']) {
+            continue;
+        }
+        lines.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    lines
+}
+
+/// The headers describing the request body, as raw header lines
+fn body_headers(headers: &HeaderMap, body: Option<&RequestBody>) -> String {
+    let len = body.map_or(0, RequestBody::len);
+    if len == 0 {
+        return String::new();
+    }
+
+    let mut lines = format!("Content-Length: {}\r\n", len);
+    if let Some(content_type) = headers.get("content-type").and_then(|value| value.to_str().ok()) {
+        lines.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    lines
+}
+
+/// Sends the request body to the client in chunks, after the request head.
+/// The client channel is bounded, so a slow tunnel holds back the reading.
+async fn send_request_body(
+    client: &ClientInfo,
+    connection_id: u64,
+    seq: &mut u64,
+    body: RequestBody,
+) -> Result<()> {
+    match body {
+        RequestBody::Memory(data) => {
+            for chunk in data.chunks(BODY_CHUNK_SIZE) {
+                send_data(client, connection_id, seq, chunk.to_vec()).await?;
+            }
+        }
+        RequestBody::Spooled(mut spool) => {
+            let mut buffer = vec![0; BODY_CHUNK_SIZE];
+            loop {
+                let n = spool.file.read(&mut buffer).await?;
+                if n == 0 {
+                    break;
+                }
+                send_data(client, connection_id, seq, buffer[..n].to_vec()).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends one frame of a connection's data, numbering it with `seq`
+async fn send_data(client: &ClientInfo, connection_id: u64, seq: &mut u64, data: Vec<u8>) -> Result<()> {
+    let message = TunnelMessage::Data {
+        connection_id,
+        seq: *seq,
+        data,
+    };
+    *seq += 1;
+    client
+        .sender
+        .send(Message::Binary(message.encode(client.framing)?))
+        .await?;
+    Ok(())
+}
+
+/// Forgets a connection and tells the client to release its side
+async fn close_stream(client: &ClientInfo, connection_id: u64) {
+    client.streams.lock().await.remove(&connection_id);
+    if let Ok(bytes) = serde_json::to_vec(&TunnelMessage::Close { connection_id }) {
+        let _ = client.sender.send(Message::Binary(bytes)).await;
+    }
+}
+
+/// Rebuilds the local service's response for the public listener
+fn build_response(parsed: http::ParsedResponse) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let mut builder = Response::builder().status(parsed.status);
+    for (name, value) in &parsed.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+
+    let body = if parsed.trailers.is_empty() {
+        full_body(parsed.body)
+    } else {
+        body_with_trailers(parsed.body, &parsed.trailers)
+    };
+
+    builder.body(body).unwrap_or_else(|_| {
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(full_body("Invalid response from local service".to_string()))
+            .unwrap()
+    })
+}
+
+/// Answers a CORS preflight request on behalf of the local service
+fn preflight_response(
+    cors: &CorsConfig,
+    origin: Option<&str>,
+    requested_headers: Option<&HeaderValue>,
+) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(full_body(""))
+        .unwrap();
+
+    let headers = response.headers_mut();
+    apply_cors_headers(cors, origin, headers);
+
+    let methods = if cors.allowed_methods.is_empty() {
+        "GET, POST, PUT, PATCH, DELETE, OPTIONS".to_string()
+    } else {
+        cors.allowed_methods.join(", ")
+    };
+    if let Ok(methods) = HeaderValue::from_str(&methods) {
+        headers.insert("access-control-allow-methods", methods);
+    }
+
+    // Allow whatever the browser asked for unless the operator restricted it
+    if cors.allowed_headers.is_empty() {
+        if let Some(requested) = requested_headers {
+            headers.insert("access-control-allow-headers", requested.clone());
+        }
+    } else if let Ok(allowed) = HeaderValue::from_str(&cors.allowed_headers.join(", ")) {
+        headers.insert("access-control-allow-headers", allowed);
+    }
+
+    response
+}
+
+/// Adds `Access-Control-Allow-Origin` if the request's origin is allowed
+fn apply_cors_headers(cors: &CorsConfig, origin: Option<&str>, headers: &mut HeaderMap) {
+    let Some(origin) = origin else {
+        return;
+    };
+
+    let allowed = if cors.allowed_origins.iter().any(|o| o == "*") {
+        "*"
+    } else if cors.allowed_origins.iter().any(|o| o == origin) {
+        origin
+    } else {
+        return;
+    };
+
+    if let Ok(allowed) = HeaderValue::from_str(allowed) {
+        headers.insert("access-control-allow-origin", allowed);
+        headers.append("vary", HeaderValue::from_static("Origin"));
+    }
+}
+
+/// Response for requests that don't belong to any tunnel, using the
+/// operator's configured redirect or landing page when set
+fn unmatched_response(options: &ServerOptions) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    if let Some(location) = &options.landing_redirect {
+        return Response::builder()
+            .status(StatusCode::FOUND)
+            .header("Location", location.as_str())
+            .body(full_body(""))
+            .unwrap();
+    }
+
+    if let Some(html) = &options.landing_page {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(full_body(html.clone()))
+            .unwrap();
+    }
+
+    not_found_response()
+}
+
+/// Mounts `path` under `base_path`, so `/` under `/myapp` becomes `/myapp/`
+fn prefix_path(base_path: &str, path: &str) -> String {
+    let base_path = base_path.trim_end_matches('/');
+    if base_path.is_empty() {
+        return path.to_string();
+    }
+
+    let separator = if base_path.starts_with('/') { "" } else { "/" };
+    format!("{}{}{}", separator, base_path, path)
 }
 
 fn not_found_response() -> Response<BoxBody<Bytes, anyhow::Error>> {
@@ -329,6 +2374,32 @@ fn not_found_response() -> Response<BoxBody<Bytes, anyhow::Error>> {
         .unwrap()
 }
 
+fn bad_request_response(message: &str) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(full_body(message.to_string()))
+        .unwrap()
+}
+
+/// A body followed by trailing headers, which gRPC-web uses to carry its status
+fn body_with_trailers(body: Vec<u8>, trailers: &[(String, String)]) -> BoxBody<Bytes, anyhow::Error> {
+    let mut trailer_map = HeaderMap::new();
+    for (name, value) in trailers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            trailer_map.append(name, value);
+        }
+    }
+
+    let frames = vec![
+        Ok(Frame::data(Bytes::from(body))),
+        Ok(Frame::trailers(trailer_map)),
+    ];
+    BodyExt::boxed(StreamBody::new(futures_util::stream::iter(frames)))
+}
+
 fn full_body<T: Into<Bytes>>(body: T) -> BoxBody<Bytes, anyhow::Error> {
     Full::new(body.into())
         .map_err(|never| match never {})
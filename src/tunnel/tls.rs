@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::TlsConfig;
+
+/// Server side of mutual TLS: presents the server certificate and refuses
+/// clients without a certificate issued by the configured CA
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(&config.ca)?))
+        .build()
+        .context("Failed to set up client certificate verification")?;
+    let server_config = rustls::ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .context("Invalid server certificate or key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Client side of mutual TLS: only trusts a server certificate issued by the
+/// configured CA and presents the client certificate
+pub fn connector(config: &TlsConfig) -> Result<TlsConnector> {
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(load_roots(&config.ca)?)
+        .with_client_auth_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .context("Invalid client certificate or key")?;
+
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// Trusts the public certificate authorities, for talking to HTTPS servers on
+/// the internet
+pub fn public_connector() -> TlsConnector {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    TlsConnector::from(Arc::new(client_config))
+}
+
+/// Accepts any certificate, since local dev servers almost always use self-signed ones
+pub fn insecure_connector() -> TlsConnector {
+    let client_config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(
+            crypto::ring::default_provider(),
+        )))
+        .with_no_client_auth();
+
+    TlsConnector::from(Arc::new(client_config))
+}
+
+/// Skips certificate validation but still checks the handshake signatures, so
+/// the connection is at least encrypted to whoever holds the presented key
+#[derive(Debug)]
+struct AcceptAnyCert(CryptoProvider);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {}", path.display()))
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse private key in {}", path.display()))?
+        .with_context(|| format!("No private key found in {}", path.display()))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+    }
+    Ok(roots)
+}
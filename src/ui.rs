@@ -1,21 +1,48 @@
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::Text,
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Sparkline, Wrap},
     Frame,
 };
 
 use crate::app::{App, AppMode};
+use crate::tunnel::Provider;
+use crate::update;
+
+/// Narrowest terminal the layout is drawn in
+const MIN_WIDTH: u16 = 50;
+
+/// Outer margin applied on every side of the layout
+const MARGIN: u16 = 2;
 
 pub fn draw(f: &mut Frame, app: &App) {
+    // Status line and help, plus the update notice, provider warning and
+    // traffic line when shown
+    let status_height = 4
+        + app.update_available.is_some() as u16
+        + app.provider_warning.is_some() as u16
+        + (app.tunnel_active && !app.recent_sizes.is_empty()) as u16;
+
+    // Latency graph, once there are requests to plot
+    let latency_height = if app.recent_durations.is_empty() { 0 } else { 5 };
+
+    // Header, status and the minimum log pane, inside the margins
+    let min_height = 2 * MARGIN + 3 + status_height + latency_height + 10;
+    let size = f.size();
+    if size.width < MIN_WIDTH || size.height < min_height {
+        draw_too_small(f, size, MIN_WIDTH, min_height);
+        return;
+    }
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .margin(2)
+        .margin(MARGIN)
         .constraints(
             [
                 Constraint::Length(3),
-                Constraint::Length(3),
+                Constraint::Length(status_height),
+                Constraint::Length(latency_height),
                 Constraint::Min(10),
             ]
             .as_ref(),
@@ -24,7 +51,33 @@ pub fn draw(f: &mut Frame, app: &App) {
 
     draw_header(f, app, chunks[0]);
     draw_status(f, app, chunks[1]);
-    draw_logs(f, app, chunks[2]);
+    if latency_height > 0 {
+        draw_latency(f, app, chunks[2]);
+    }
+    if app.mode == AppMode::PickProvider {
+        draw_provider_picker(f, app, chunks[3]);
+    } else {
+        draw_logs(f, app, chunks[3]);
+    }
+
+    if app.mode == AppMode::ShowUrl {
+        draw_url_popup(f, app, size);
+    }
+    if app.mode == AppMode::ShowCapture {
+        draw_capture_popup(f, app, size);
+    }
+}
+
+/// Replaces the layout with a hint to resize when it wouldn't fit
+fn draw_too_small(f: &mut Frame, area: Rect, min_width: u16, min_height: u16) {
+    let message = format!(
+        "Terminal too small (need ≥ {}x{}, have {}x{})",
+        min_width, min_height, area.width, area.height
+    );
+    let widget = Paragraph::new(message)
+        .style(Style::default().fg(Color::Yellow))
+        .wrap(Wrap { trim: true });
+    f.render_widget(widget, area);
 }
 
 fn draw_header(f: &mut Frame, _app: &App, area: Rect) {
@@ -45,6 +98,10 @@ fn draw_status(f: &mut Frame, app: &App, area: Rect) {
             draw_config_input(f, app, area, "Configure Server Port", "Enter new server port value:", &app.input_buffer);
             return;
         }
+        AppMode::PickProvider => {
+            draw_config_input(f, app, area, "Choose Provider", "Select a tunnel provider with [↑/↓]", "");
+            return;
+        }
         _ => {}
     }
 
@@ -57,33 +114,106 @@ fn draw_status(f: &mut Frame, app: &App, area: Rect) {
         color = Color::Red;
     } else if app.tunnel_active {
         // Show active tunnel
-        status_text = format!(
-            "Tunnel active: localhost:{} -> {}",
-            app.port,
+        let mut text = format!(
+            "Tunnel active: {} -> {}",
+            app.local_target(),
             app.tunnel_url.as_ref().unwrap()
         );
+        // Only the builtin provider sees individual requests
+        if app.provider == Provider::Builtin {
+            let (active, queued) = app.in_flight;
+            match app.max_concurrent {
+                Some(max) => text.push_str(&format!(" [{}/{} in flight", active, max)),
+                None => text.push_str(&format!(" [{} in flight", active)),
+            }
+            if queued > 0 {
+                text.push_str(&format!(", {} queued", queued));
+            }
+            text.push(']');
+        }
+        if let Some(remaining) = app.quota_remaining {
+            text.push_str(&format!(" [{} MB left]", remaining / (1024 * 1024)));
+        }
+        if let Some(reason) = &app.last_disconnect_reason {
+            text.push_str(&format!(
+                " (reconnects: {}, last disconnect: {})",
+                app.reconnect_count, reason
+            ));
+        }
+        status_text = text;
         color = Color::Green;
     } else {
         // Show inactive state
         status_text = format!(
-            "Tunnel inactive. Press 's' to start tunnel on port {}",
-            app.port
+            "Tunnel inactive. Press 's' to start a {} tunnel for {}",
+            app.provider.name(),
+            app.local_target()
         );
         color = Color::Yellow;
     }
 
-    let help = " [s] Start/Stop  [p] Configure port  [P] Configure server port  [q] Quit  [↑/↓] Scroll logs";
+    let help = " [s] Start/Stop  [R] Restart  [p] Configure port  [P] Configure server port  [o/t] Pick/cycle provider  [n] New URL  [C] Clear logs  [S] Errors-only logs  [T] Log timestamps  [space] Pause/follow logs  [x] Share command  [e] Export logs  [u] Show URL  [c] Copy URL  [b] Captured bodies  [q] Quit  [↑/↓] Scroll logs";
 
-    let paragraphs = [status_text, help.to_string()];
-    let text = paragraphs.join("\n");
+    let mut lines = vec![Line::from(Span::styled(
+        status_text,
+        Style::default().fg(color),
+    ))];
+    if app.tunnel_active {
+        if let (Some((last_req, last_res)), Some((avg_req, avg_res))) =
+            (app.recent_sizes.back(), app.average_sizes())
+        {
+            lines.push(Line::from(format!(
+                "Requests: {}  Last: {} in / {} out  Avg: {} in / {} out",
+                app.request_count,
+                format_bytes(*last_req),
+                format_bytes(*last_res),
+                format_bytes(avg_req),
+                format_bytes(avg_res)
+            )));
+        }
+    }
+    if let Some(latest) = &app.update_available {
+        lines.push(Line::from(Span::styled(
+            format!(
+                "Update available: rshare {} (running {})",
+                latest,
+                update::CURRENT_VERSION
+            ),
+            Style::default().fg(Color::Cyan),
+        )));
+    }
+    if let Some(warning) = &app.provider_warning {
+        lines.push(Line::from(Span::styled(
+            format!("WARNING: {}", warning),
+            Style::default().fg(Color::Yellow),
+        )));
+    }
+    lines.push(Line::from(help));
 
-    let status_widget = Paragraph::new(text)
-        .style(Style::default().fg(color))
+    let status_widget = Paragraph::new(lines)
         .block(Block::default().borders(Borders::ALL).title("Status"));
 
     f.render_widget(status_widget, area);
 }
 
+/// Formats a byte count with a binary unit, e.g. `1.5 KB`
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
 fn draw_config_input(f: &mut Frame, _app: &App, area: Rect, title: &str, prompt: &str, input: &str) {
     let input_text = format!("{} {}\n[Enter] Save  [Esc] Cancel", prompt, input);
     
@@ -94,15 +224,149 @@ fn draw_config_input(f: &mut Frame, _app: &App, area: Rect, title: &str, prompt:
     f.render_widget(input_widget, area);
 }
 
+/// Sparkline of recent request durations, newest on the right
+fn draw_latency(f: &mut Frame, app: &App, area: Rect) {
+    // Only as many points as fit inside the borders
+    let width = area.width.saturating_sub(2) as usize;
+    let skip = app.recent_durations.len().saturating_sub(width);
+    let data: Vec<u64> = app.recent_durations.iter().skip(skip).copied().collect();
+    let max = data.iter().copied().max().unwrap_or(0);
+
+    let title = format!(
+        "Latency (last {} requests, max {} ms, latest {} ms)",
+        data.len(),
+        max,
+        data.last().copied().unwrap_or(0)
+    );
+    let sparkline = Sparkline::default()
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .data(&data)
+        .style(Style::default().fg(Color::Magenta));
+
+    f.render_widget(sparkline, area);
+}
+
+fn draw_provider_picker(f: &mut Frame, app: &App, area: Rect) {
+    let items: Vec<ListItem> = app
+        .provider_choices
+        .iter()
+        .map(|(provider, installed)| {
+            let (status, color) = if *installed {
+                ("ready".to_string(), Color::Green)
+            } else {
+                (format!("not installed: {}", provider.install_hint()), Color::Red)
+            };
+            ListItem::new(Line::from(vec![
+                Span::raw(format!("{:<12}", provider.name())),
+                Span::styled(status, Style::default().fg(color)),
+            ]))
+        })
+        .collect();
+
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Providers"))
+        .highlight_style(
+            Style::default()
+                .bg(Color::DarkGray)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+
+    let mut state = ListState::default().with_selected(Some(app.provider_selection));
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+/// Shows the whole tunnel URL over the rest of the UI, wrapped rather than
+/// truncated, so it can be read or selected however narrow the status bar is
+fn draw_url_popup(f: &mut Frame, app: &App, area: Rect) {
+    let url = app.tunnel_url.as_deref().unwrap_or_default();
+
+    // Fit the URL on one line when there's room, otherwise let it wrap
+    let width = (url.chars().count() as u16 + 4).clamp(30, area.width.saturating_sub(4));
+    let inner_width = width.saturating_sub(2).max(1);
+    let url_lines = (url.chars().count() as u16).div_ceil(inner_width).max(1);
+    let height = (url_lines + 4).min(area.height);
+
+    let popup = Rect {
+        x: area.x + (area.width.saturating_sub(width)) / 2,
+        y: area.y + (area.height.saturating_sub(height)) / 2,
+        width,
+        height,
+    };
+
+    let text = vec![
+        Line::from(Span::styled(url, Style::default().add_modifier(Modifier::BOLD))),
+        Line::from(""),
+        Line::from(Span::styled("[Esc] Close", Style::default().fg(Color::DarkGray))),
+    ];
+    let widget = Paragraph::new(text)
+        .wrap(Wrap { trim: false })
+        .block(Block::default().borders(Borders::ALL).title("Tunnel URL"));
+
+    f.render_widget(Clear, popup);
+    f.render_widget(widget, popup);
+}
+
+fn draw_capture_popup(f: &mut Frame, app: &App, area: Rect) {
+    let Some(entries) = app.captures.as_ref().map(|captures| captures.entries()) else {
+        return;
+    };
+    let Some(capture) = entries.iter().rev().nth(app.capture_selection) else {
+        return;
+    };
+
+    let popup = Rect {
+        x: area.x + area.width / 10,
+        y: area.y + area.height / 10,
+        width: area.width - area.width / 5,
+        height: area.height - area.height / 5,
+    };
+
+    let heading = Style::default().add_modifier(Modifier::BOLD);
+    let mut lines = vec![Line::from(Span::styled("Request", heading))];
+    lines.extend(capture.request.lines().map(Line::from));
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled("Response", heading)));
+    lines.extend(capture.response.lines().map(Line::from));
+
+    let title = format!(
+        "Captured request {} of {}{}, {}s ago ([←/→] Older/newer  [↑/↓] Scroll  [Esc] Close)",
+        entries.len() - app.capture_selection,
+        entries.len(),
+        capture
+            .trace
+            .as_deref()
+            .map(|trace| format!(" [{}]", trace))
+            .unwrap_or_default(),
+        capture.captured_at.elapsed().as_secs()
+    );
+    let widget = Paragraph::new(lines)
+        .wrap(Wrap { trim: false })
+        .scroll((app.capture_scroll, 0))
+        .block(Block::default().borders(Borders::ALL).title(title));
+
+    f.render_widget(Clear, popup);
+    f.render_widget(widget, popup);
+}
+
 fn draw_logs(f: &mut Frame, app: &App, area: Rect) {
+    let title = match (app.follow_logs, app.silent_logs) {
+        (true, false) => "Logs",
+        (true, true) => "Logs (errors only)",
+        (false, false) => "Logs (paused, [space] to follow)",
+        (false, true) => "Logs (errors only, paused, [space] to follow)",
+    };
+
+    // Leave room for the borders
+    let height = area.height.saturating_sub(2) as usize;
     let logs: Vec<ListItem> = app
-        .visible_logs()
-        .iter()
-        .map(|log| ListItem::new(log.as_str()))
+        .visible_logs(height)
+        .into_iter()
+        .map(ListItem::new)
         .collect();
 
     let logs = List::new(logs)
-        .block(Block::default().borders(Borders::ALL).title("Logs"))
+        .block(Block::default().borders(Borders::ALL).title(title))
         .highlight_style(
             Style::default()
                 .bg(Color::DarkGray)
@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Context, Result};
+use http_body_util::BodyExt;
+use hyper::header::{HeaderValue, ACCEPT};
+use hyper::{StatusCode, Uri};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::timeout;
+
+use crate::http_client;
+
+/// Release API endpoint returning the most recent published release
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/plyght/rshare/releases/latest";
+
+/// Give up on the check rather than hold anything up on a slow network
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The version of this build
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+/// Returns the latest published version if it is newer than this build
+pub async fn newer_release() -> Result<Option<String>> {
+    let latest = timeout(CHECK_TIMEOUT, latest_version())
+        .await
+        .map_err(|_| anyhow!("Timed out checking for updates"))??;
+    Ok(is_newer(&latest, CURRENT_VERSION).then_some(latest))
+}
+
+/// Asks the release API for the latest tag, without any leading `v`
+async fn latest_version() -> Result<String> {
+    let uri: Uri = LATEST_RELEASE_URL.parse()?;
+    let mut connection = http_client::connect(&uri)
+        .await
+        .context("Failed to reach the release server")?;
+    let mut request = http_client::get(&uri)?;
+    request
+        .headers_mut()
+        .insert(ACCEPT, HeaderValue::from_static("application/vnd.github+json"));
+
+    let response = connection.send_request(request).await?;
+    if response.status() != StatusCode::OK {
+        return Err(anyhow!("Release server answered with status {}", response.status()));
+    }
+    let body = response.into_body().collect().await?.to_bytes();
+    let release: Release = serde_json::from_slice(&body)?;
+    Ok(release.tag_name.trim_start_matches('v').to_string())
+}
+
+/// Compares dotted versions numerically, ignoring any pre-release suffix
+fn is_newer(latest: &str, current: &str) -> bool {
+    fn parts(version: &str) -> Vec<u64> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    }
+    parts(latest) > parts(current)
+}